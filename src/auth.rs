@@ -0,0 +1,144 @@
+// Signed publish urls: the stream key carries a "token" and an "expires" query parameter, where the
+// token is the hex encoded HMAC-SHA256 of "<app>/<stream key>/<expires>" with the shared secret and
+// expires is a unix timestamp in seconds.
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Splits the stream key sent by the publisher into the channel name and its query string
+pub fn split_stream_key(stream_key: &str) -> (&str, Option<&str>) {
+    match stream_key.find('?') {
+        Some(index) => (&stream_key[..index], Some(&stream_key[index + 1..])),
+        None => (stream_key, None),
+    }
+}
+
+/// Checks the token and expiry found in the query string of the stream key
+pub fn verify(
+    secret: &str,
+    app_name: &str,
+    stream_key: &str,
+    query: Option<&str>,
+) -> Result<(), String> {
+    let query = query.ok_or_else(|| "Missing token".to_string())?;
+    let token = query_value(query, "token").ok_or_else(|| "Missing token".to_string())?;
+    let expires = query_value(query, "expires")
+        .ok_or_else(|| "Missing expiry".to_string())?
+        .parse::<u64>()
+        .map_err(|_| "Invalid expiry".to_string())?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0);
+    if expires < now {
+        return Err(format!("Token expired at {}", expires));
+    }
+
+    let token = hex::decode(token).map_err(|_| "Invalid token".to_string())?;
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(signed_message(app_name, stream_key, expires).as_bytes());
+    // Constant time comparison
+    mac.verify(&token).map_err(|_| "Invalid token".to_string())
+}
+
+fn signed_message(app_name: &str, stream_key: &str, expires: u64) -> String {
+    format!("{}/{}/{}", app_name, stream_key, expires)
+}
+
+fn query_value<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key == name => Some(value),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // HMAC-SHA256 of "live/somekey/4102444800" (2100-01-01) with "secret"
+    const TOKEN: &str = "ae2263e6bc88364825dac7f5d3ea7c82e6e1ab21bd85930f99c756c98379f962";
+    // HMAC-SHA256 of "live/somekey/1" with "secret"
+    const EXPIRED_TOKEN: &str = "07e3da460b884f1d3bf4ae34c54568d49075c820e9be3b19458be572689836e5";
+
+    fn signed_query(token: &str, expires: u64) -> String {
+        format!("token={}&expires={}", token, expires)
+    }
+
+    #[test]
+    fn splits_stream_key() {
+        assert_eq!(split_stream_key("somekey"), ("somekey", None));
+        assert_eq!(
+            split_stream_key("somekey?token=abc&expires=1"),
+            ("somekey", Some("token=abc&expires=1"))
+        );
+        assert_eq!(split_stream_key("somekey?"), ("somekey", Some("")));
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        let query = signed_query(TOKEN, 4_102_444_800);
+        assert_eq!(verify("secret", "live", "somekey", Some(&query)), Ok(()));
+
+        // The parameters can come in any order, along with others
+        let query = format!("expires=4102444800&foo=bar&token={}", TOKEN);
+        assert_eq!(verify("secret", "live", "somekey", Some(&query)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_signature() {
+        let query = signed_query(TOKEN, 4_102_444_800);
+        assert!(verify("other", "live", "somekey", Some(&query)).is_err());
+        assert!(verify("secret", "other", "somekey", Some(&query)).is_err());
+        assert!(verify("secret", "live", "otherkey", Some(&query)).is_err());
+
+        // The expiry is signed as well
+        let query = signed_query(TOKEN, 4_102_444_801);
+        assert!(verify("secret", "live", "somekey", Some(&query)).is_err());
+
+        let query = signed_query("not hex", 4_102_444_800);
+        assert_eq!(
+            verify("secret", "live", "somekey", Some(&query)),
+            Err("Invalid token".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let query = signed_query(EXPIRED_TOKEN, 1);
+        assert_eq!(
+            verify("secret", "live", "somekey", Some(&query)),
+            Err("Token expired at 1".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_missing_parameters() {
+        assert_eq!(
+            verify("secret", "live", "somekey", None),
+            Err("Missing token".to_string())
+        );
+        assert_eq!(
+            verify("secret", "live", "somekey", Some("expires=4102444800")),
+            Err("Missing token".to_string())
+        );
+
+        let query = format!("token={}", TOKEN);
+        assert_eq!(
+            verify("secret", "live", "somekey", Some(&query)),
+            Err("Missing expiry".to_string())
+        );
+
+        let query = format!("token={}&expires=soon", TOKEN);
+        assert_eq!(
+            verify("secret", "live", "somekey", Some(&query)),
+            Err("Invalid expiry".to_string())
+        );
+    }
+}
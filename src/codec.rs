@@ -0,0 +1,460 @@
+// Parsers of the decoder configurations carried in the sequence headers, the AVCDecoderConfiguration
+// record with its SPS (ISO/IEC 14496-15 and ITU-T H.264 7.3.2.1) and the AudioSpecificConfig of AAC
+// (ISO/IEC 14496-3 1.6.2.1).
+
+/// Properties of the H.264 stream described by its first SPS
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvcConfig {
+    pub profile: &'static str,
+    pub level: String,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: Option<(i32, i32)>,
+    pub pixel_aspect_ratio: Option<(i32, i32)>,
+}
+
+// Sample aspect ratios of the aspect_ratio_idc values of the VUI (Table E-1)
+const PIXEL_ASPECT_RATIOS: [(i32, i32); 16] = [
+    (1, 1),
+    (12, 11),
+    (10, 11),
+    (16, 11),
+    (40, 33),
+    (24, 11),
+    (20, 11),
+    (32, 11),
+    (80, 33),
+    (18, 11),
+    (15, 11),
+    (64, 33),
+    (160, 99),
+    (4, 3),
+    (3, 2),
+    (2, 1),
+];
+const ASPECT_RATIO_EXTENDED_SAR: u32 = 255;
+
+impl AvcConfig {
+    pub fn parse(record: &[u8]) -> Option<AvcConfig> {
+        if record.len() < 8 || record[0] != 1 || record[5] & 0x1f == 0 {
+            return None;
+        }
+
+        let sps_length = u16::from_be_bytes([record[6], record[7]]) as usize;
+        // Skip the NAL unit header
+        let sps = record.get(9..8 + sps_length)?;
+        AvcConfig::parse_sps(&remove_emulation_prevention(sps))
+    }
+
+    fn parse_sps(sps: &[u8]) -> Option<AvcConfig> {
+        let mut reader = BitReader::new(sps);
+        let profile_idc = reader.read_bits(8)?;
+        let constraint_flags = reader.read_bits(8)?;
+        let level_idc = reader.read_bits(8)?;
+        reader.read_ue()?; // seq_parameter_set_id
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        // The high profiles describe their chroma format and bit depth
+        if matches!(
+            profile_idc,
+            100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+        ) {
+            chroma_format_idc = reader.read_ue()?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = reader.read_bit()?;
+            }
+            reader.read_ue()?; // bit_depth_luma_minus8
+            reader.read_ue()?; // bit_depth_chroma_minus8
+            reader.read_bit()?; // qpprime_y_zero_transform_bypass_flag
+            if reader.read_bit()? {
+                let count = if chroma_format_idc == 3 { 12 } else { 8 };
+                for i in 0..count {
+                    if reader.read_bit()? {
+                        reader.skip_scaling_list(if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        reader.read_ue()?; // log2_max_frame_num_minus4
+        match reader.read_ue()? {
+            0 => {
+                reader.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
+            }
+            1 => {
+                reader.read_bit()?; // delta_pic_order_always_zero_flag
+                reader.read_se()?; // offset_for_non_ref_pic
+                reader.read_se()?; // offset_for_top_to_bottom_field
+                for _ in 0..reader.read_ue()? {
+                    reader.read_se()?; // offset_for_ref_frame
+                }
+            }
+            _ => (),
+        }
+        reader.read_ue()?; // max_num_ref_frames
+        reader.read_bit()?; // gaps_in_frame_num_value_allowed_flag
+
+        let width_in_mbs = reader.read_ue()?.checked_add(1)?;
+        let height_in_map_units = reader.read_ue()?.checked_add(1)?;
+        let frame_mbs_only = reader.read_bit()?;
+        if !frame_mbs_only {
+            reader.read_bit()?; // mb_adaptive_frame_field_flag
+        }
+        reader.read_bit()?; // direct_8x8_inference_flag
+
+        let mut crop = [0; 4];
+        if reader.read_bit()? {
+            for value in crop.iter_mut() {
+                *value = reader.read_ue()?;
+            }
+        }
+
+        // Cropping is expressed in chroma samples, of which there is none for monochrome streams
+        let (crop_unit_x, crop_unit_y) = match (chroma_format_idc, separate_colour_plane) {
+            (0, _) | (3, true) => (1, 1),
+            (1, _) => (2, 2),
+            (2, _) => (2, 1),
+            _ => (1, 1),
+        };
+        // The values come from the publisher, a crafted SPS must not overflow
+        let field_count = if frame_mbs_only { 1 } else { 2 };
+        let crop_unit_y = crop_unit_y * field_count;
+        let width = width_in_mbs
+            .checked_mul(16)?
+            .checked_sub(crop[0].checked_add(crop[1])?.checked_mul(crop_unit_x)?)?;
+        let height = height_in_map_units
+            .checked_mul(16 * field_count)?
+            .checked_sub(crop[2].checked_add(crop[3])?.checked_mul(crop_unit_y)?)?;
+
+        let mut config = AvcConfig {
+            profile: avc_profile(profile_idc, constraint_flags),
+            level: avc_level(profile_idc, constraint_flags, level_idc),
+            width,
+            height,
+            framerate: None,
+            pixel_aspect_ratio: None,
+        };
+
+        // The VUI is optional, whatever could be read so far is still valid without it
+        if reader.read_bit() == Some(true) {
+            reader.parse_vui(&mut config);
+        }
+
+        Some(config)
+    }
+}
+
+fn avc_profile(profile_idc: u32, constraint_flags: u32) -> &'static str {
+    match profile_idc {
+        66 if constraint_flags & 0x40 != 0 => "constrained-baseline",
+        66 => "baseline",
+        77 => "main",
+        88 => "extended",
+        100 => "high",
+        110 => "high-10",
+        122 => "high-4:2:2",
+        244 => "high-4:4:4",
+        44 => "cavlc-4:4:4-intra",
+        _ => "unknown",
+    }
+}
+
+fn avc_level(profile_idc: u32, constraint_flags: u32, level_idc: u32) -> String {
+    match level_idc {
+        9 => "1b".to_string(),
+        // Level 1b of the baseline, main and extended profiles is signaled with constraint_set3
+        11 if constraint_flags & 0x10 != 0 && (profile_idc == 66 || profile_idc == 77) => {
+            "1b".to_string()
+        }
+        level if level % 10 == 0 => (level / 10).to_string(),
+        level => format!("{}.{}", level / 10, level % 10),
+    }
+}
+
+/// Properties of the AAC stream described by its AudioSpecificConfig
+#[derive(Debug, Clone, PartialEq)]
+pub struct AacConfig {
+    pub profile: Option<&'static str>,
+    /// Output sample rate, including the spectral band replication of HE-AAC
+    pub rate: u32,
+    /// Unknown when the channels are described by a program config element
+    pub channels: Option<u32>,
+}
+
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96_000, 88_200, 64_000, 48_000, 44_100, 32_000, 24_000, 22_050, 16_000, 12_000, 11_025, 8_000,
+    7_350,
+];
+const AAC_OBJECT_TYPE_SBR: u32 = 5;
+const AAC_OBJECT_TYPE_PS: u32 = 29;
+
+impl AacConfig {
+    pub fn parse(config: &[u8]) -> Option<AacConfig> {
+        let mut reader = BitReader::new(config);
+        let mut object_type = read_aac_object_type(&mut reader)?;
+        let mut rate = read_aac_sample_rate(&mut reader)?;
+        let channel_configuration = reader.read_bits(4)?;
+
+        // HE-AAC signals the rate of the decoded output after the one of the core
+        if object_type == AAC_OBJECT_TYPE_SBR || object_type == AAC_OBJECT_TYPE_PS {
+            rate = read_aac_sample_rate(&mut reader)?;
+            object_type = read_aac_object_type(&mut reader)?;
+        }
+
+        let profile = match object_type {
+            1 => Some("main"),
+            2 => Some("lc"),
+            3 => Some("ssr"),
+            4 => Some("ltp"),
+            _ => None,
+        };
+        let channels = match channel_configuration {
+            0 => None,
+            7 => Some(8),
+            1..=6 => Some(channel_configuration),
+            _ => None,
+        };
+
+        Some(AacConfig {
+            profile,
+            rate,
+            channels,
+        })
+    }
+}
+
+fn read_aac_object_type(reader: &mut BitReader) -> Option<u32> {
+    match reader.read_bits(5)? {
+        31 => Some(32 + reader.read_bits(6)?),
+        object_type => Some(object_type),
+    }
+}
+
+fn read_aac_sample_rate(reader: &mut BitReader) -> Option<u32> {
+    match reader.read_bits(4)? {
+        15 => reader.read_bits(24),
+        index => AAC_SAMPLE_RATES.get(index as usize).copied(),
+    }
+}
+
+/// Strips the 0x03 bytes inserted after two zero bytes in the NAL units
+fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.data.get(self.position / 8)?;
+        let bit = byte >> (7 - self.position % 8) & 1;
+        self.position += 1;
+        Some(bit == 1)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | self.read_bit()? as u32;
+        }
+        Some(value)
+    }
+
+    /// Unsigned Exp-Golomb code
+    fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u64 << leading_zeros) as u32 - 1 + self.read_bits(leading_zeros)?)
+    }
+
+    /// Signed Exp-Golomb code
+    fn read_se(&mut self) -> Option<i32> {
+        let value = self.read_ue()? as i64;
+        Some(if value % 2 == 1 {
+            (value + 1) / 2
+        } else {
+            -(value / 2)
+        } as i32)
+    }
+
+    fn skip_scaling_list(&mut self, size: u32) -> Option<()> {
+        let mut last_scale = 8;
+        let mut next_scale = 8;
+        for _ in 0..size {
+            if next_scale != 0 {
+                // delta_scale is meant to be within -128..=127, but is not trusted to be
+                next_scale = (last_scale + self.read_se()? as i64).rem_euclid(256);
+            }
+            if next_scale != 0 {
+                last_scale = next_scale;
+            }
+        }
+        Some(())
+    }
+
+    /// Reads the aspect ratio and timing of the VUI parameters (E.1.1)
+    fn parse_vui(&mut self, config: &mut AvcConfig) -> Option<()> {
+        if self.read_bit()? {
+            let aspect_ratio_idc = self.read_bits(8)?;
+            config.pixel_aspect_ratio = if aspect_ratio_idc == ASPECT_RATIO_EXTENDED_SAR {
+                let width = self.read_bits(16)? as i32;
+                let height = self.read_bits(16)? as i32;
+                Some((width, height)).filter(|&(width, height)| width > 0 && height > 0)
+            } else {
+                // 0 is unspecified
+                (aspect_ratio_idc as usize)
+                    .checked_sub(1)
+                    .and_then(|index| PIXEL_ASPECT_RATIOS.get(index))
+                    .copied()
+            };
+        }
+
+        if self.read_bit()? {
+            self.read_bit()?; // overscan_appropriate_flag
+        }
+
+        if self.read_bit()? {
+            self.read_bits(4)?; // video_format and video_full_range_flag
+            if self.read_bit()? {
+                self.read_bits(24)?; // colour_primaries, transfer and matrix coefficients
+            }
+        }
+
+        if self.read_bit()? {
+            self.read_ue()?; // chroma_sample_loc_type_top_field
+            self.read_ue()?; // chroma_sample_loc_type_bottom_field
+        }
+
+        if self.read_bit()? {
+            let num_units_in_tick = self.read_bits(32)?;
+            let time_scale = self.read_bits(32)?;
+            // A frame lasts two ticks, one per field
+            if num_units_in_tick > 0 && time_scale > 0 {
+                let denominator = num_units_in_tick as u64 * 2;
+                let (numerator, denominator) = reduce(time_scale as u64, denominator);
+                if numerator <= i32::MAX as u64 && denominator <= i32::MAX as u64 {
+                    config.framerate = Some((numerator as i32, denominator as i32));
+                }
+            }
+        }
+
+        Some(())
+    }
+}
+
+fn reduce(numerator: u64, denominator: u64) -> (u64, u64) {
+    let (mut a, mut b) = (numerator, denominator);
+    while b != 0 {
+        let remainder = a % b;
+        a = b;
+        b = remainder;
+    }
+    (numerator / a, denominator / a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wraps the SPS in an AVCDecoderConfigurationRecord, without any PPS
+    fn record(sps: &[u8]) -> Vec<u8> {
+        let mut record = vec![1, sps[1], sps[2], sps[3], 0xff, 0xe1];
+        record.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+        record.extend_from_slice(sps);
+        record.push(0);
+        record
+    }
+
+    fn hex(value: &str) -> Vec<u8> {
+        (0..value.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_x264_sps() {
+        // 1080p30 High 4.0, the 1088 coded lines are cropped to 1080
+        let sps = hex("67640028acd940780227e5c044000003000400000300f03c60c658");
+        assert_eq!(
+            AvcConfig::parse(&record(&sps)),
+            Some(AvcConfig {
+                profile: "high",
+                level: "4".to_string(),
+                width: 1920,
+                height: 1080,
+                framerate: Some((30, 1)),
+                pixel_aspect_ratio: Some((1, 1)),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_cropped_interlaced_sps() {
+        // 720x576 in field pairs, cropped by 8 columns and 16 lines, without VUI
+        let sps = hex("674d401fec805a12396540");
+        assert_eq!(
+            AvcConfig::parse(&record(&sps)),
+            Some(AvcConfig {
+                profile: "main",
+                level: "3.1".to_string(),
+                width: 712,
+                height: 560,
+                framerate: None,
+                pixel_aspect_ratio: None,
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_timing_of_unspecified_aspect_ratio() {
+        // aspect_ratio_idc 0 followed by the timing of 25 frames per second
+        let sps = hex("674d401fec805017fcb80080000003008000001942");
+        let config = AvcConfig::parse(&record(&sps)).unwrap();
+        assert_eq!((config.width, config.height), (640, 360));
+        assert_eq!(config.pixel_aspect_ratio, None);
+        assert_eq!(config.framerate, Some((25, 1)));
+    }
+
+    #[test]
+    fn rejects_overflowing_dimensions() {
+        // pic_width_in_mbs_minus1 of 2^32 - 2 and crop offsets of 2^31
+        let sps = hex("674d401fec80000003001fffffffeb8000000300800000030100000300010000030003a0");
+        assert_eq!(AvcConfig::parse(&record(&sps)), None);
+    }
+
+    #[test]
+    fn parses_aac_config() {
+        assert_eq!(
+            AacConfig::parse(&[0x12, 0x10]),
+            Some(AacConfig {
+                profile: Some("lc"),
+                rate: 44_100,
+                channels: Some(2),
+            })
+        );
+    }
+}
@@ -1,13 +1,22 @@
 use rml_rtmp::handshake::{Handshake, HandshakeProcessResult, PeerType};
+use rustls::Session;
 use std::collections::VecDeque;
 use std::io;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{Shutdown, TcpStream};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 const BUFFER_SIZE: usize = 4096;
+/// Time given to the pending writes of a closed connection, all together
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+/// A peer not reading for that long is disconnected, so that no write blocks forever
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// A TLS session is driven by a single thread, which alternates between reading and writing
+const TLS_POLL_INTERVAL: Duration = Duration::from_millis(5);
 
 pub enum ReadResult {
     HandshakingInProgress,
@@ -32,31 +41,91 @@ impl From<io::Error> for ConnectionError {
 
 pub struct Connection {
     pub connection_id: Option<usize>,
+    socket: TcpStream,
     writer: Sender<Vec<u8>>,
     reader: Receiver<ReadResult>,
+    writer_handle: JoinHandle<()>,
+    /// Disconnected once the writer thread finished
+    writer_done: Receiver<()>,
+    /// Not set for TLS connections, which read and write from the same thread
+    reader_handle: Option<JoinHandle<()>>,
     handshake: Handshake,
     handshake_completed: bool,
+    created_at: Instant,
 }
 
 impl Connection {
-    pub fn new(socket: TcpStream) -> Connection {
+    pub fn new(socket: TcpStream, tls: Option<&Arc<rustls::ServerConfig>>) -> Connection {
         let (byte_sender, byte_receiver) = channel();
         let (result_sender, result_receiver) = channel();
+        let (done_sender, done_receiver) = channel();
+        if let Err(error) = socket.set_write_timeout(Some(WRITE_TIMEOUT)) {
+            println!("Failed to set the write timeout: {:?}", error);
+        }
 
-        start_byte_writer(byte_receiver, &socket);
-        start_result_reader(result_sender, &socket);
+        let (writer_handle, reader_handle) = match tls {
+            Some(config) => {
+                let session = rustls::ServerSession::new(config);
+                let handle =
+                    start_tls_io(byte_receiver, result_sender, done_sender, &socket, session);
+                (handle, None)
+            }
+            None => (
+                start_byte_writer(byte_receiver, done_sender, &socket),
+                Some(start_result_reader(result_sender, &socket)),
+            ),
+        };
 
         Connection {
             connection_id: None,
+            socket,
             writer: byte_sender,
             reader: result_receiver,
+            writer_handle,
+            writer_done: done_receiver,
+            reader_handle,
             handshake: Handshake::new(PeerType::Server),
             handshake_completed: false,
+            created_at: Instant::now(),
+        }
+    }
+
+    /// Flushes the pending writes, closes the socket and waits for the I/O threads to finish
+    pub fn close(self) {
+        let Connection {
+            socket,
+            writer,
+            reader,
+            writer_handle,
+            writer_done,
+            reader_handle,
+            ..
+        } = self;
+
+        // A peer not reading anymore gets the pending writes cut short, shutting the socket down
+        // unblocks the writer thread
+        drop(writer);
+        let _ = writer_done.recv_timeout(CLOSE_TIMEOUT);
+        let _ = socket.shutdown(Shutdown::Both);
+        let _ = writer_handle.join();
+
+        drop(reader);
+        if let Some(reader_handle) = reader_handle {
+            let _ = reader_handle.join();
+        }
+    }
+
+    /// Time spent on the handshake so far, none once it completed
+    pub fn handshake_duration(&self) -> Option<Duration> {
+        match self.handshake_completed {
+            true => None,
+            false => Some(self.created_at.elapsed()),
         }
     }
 
     pub fn write(&self, bytes: Vec<u8>) {
-        self.writer.send(bytes).unwrap();
+        // The writer thread only stops on socket errors, which are noticed by the reader as well
+        let _ = self.writer.send(bytes);
     }
 
     pub fn read(&mut self) -> Result<ReadResult, ConnectionError> {
@@ -119,35 +188,44 @@ impl Connection {
     }
 }
 
-fn start_byte_writer(byte_receiver: Receiver<Vec<u8>>, socket: &TcpStream) {
+fn start_byte_writer(
+    byte_receiver: Receiver<Vec<u8>>,
+    done_sender: Sender<()>,
+    socket: &TcpStream,
+) -> JoinHandle<()> {
     let mut socket = socket.try_clone().expect("failed to clone socket");
     thread::spawn(move || {
+        let _done_sender = done_sender;
         let mut send_queue = VecDeque::new();
+        let mut disconnected = false;
 
         loop {
-            loop {
+            while !disconnected {
                 match byte_receiver.try_recv() {
                     Err(TryRecvError::Empty) => break,
-                    Err(TryRecvError::Disconnected) => return,
+                    Err(TryRecvError::Disconnected) => disconnected = true,
                     Ok(bytes) => send_queue.push_back(bytes),
                 }
             }
 
             match send_queue.pop_front() {
+                // Everything queued before the connection was closed got written
+                None if disconnected => return,
                 None => thread::sleep(Duration::from_millis(1)),
-                Some(bytes) => match socket.write(&bytes) {
-                    Ok(_) => (),
-                    Err(error) => {
+                Some(bytes) => {
+                    if let Err(error) = socket.write_all(&bytes) {
                         println!("Error writing to socket: {:?}", error);
+                        // Let the reader notice the connection is gone
+                        let _ = socket.shutdown(Shutdown::Both);
                         return;
                     }
-                },
+                }
             }
         }
-    });
+    })
 }
 
-fn start_result_reader(sender: Sender<ReadResult>, socket: &TcpStream) {
+fn start_result_reader(sender: Sender<ReadResult>, socket: &TcpStream) -> JoinHandle<()> {
     let mut socket = socket.try_clone().unwrap();
     thread::spawn(move || {
         let mut buffer = [0; BUFFER_SIZE];
@@ -165,14 +243,110 @@ fn start_result_reader(sender: Sender<ReadResult>, socket: &TcpStream) {
                         byte_count: read_count,
                     };
 
-                    sender.send(result).unwrap();
+                    if sender.send(result).is_err() {
+                        return; // connection closed
+                    }
+                }
+
+                Err(error) => {
+                    println!("Error occurred reading from socket: {:?}", error);
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// Encrypts the outgoing bytes and decrypts the incoming ones of a RTMPS connection
+fn start_tls_io(
+    byte_receiver: Receiver<Vec<u8>>,
+    sender: Sender<ReadResult>,
+    done_sender: Sender<()>,
+    socket: &TcpStream,
+    mut session: rustls::ServerSession,
+) -> JoinHandle<()> {
+    let mut socket = socket.try_clone().expect("failed to clone socket");
+    thread::spawn(move || {
+        let _done_sender = done_sender;
+        if let Err(error) = socket.set_read_timeout(Some(TLS_POLL_INTERVAL)) {
+            println!("Failed to configure TLS socket: {:?}", error);
+            return;
+        }
+
+        let mut buffer = [0; BUFFER_SIZE];
+        let mut disconnected = false;
+        loop {
+            while !disconnected {
+                match byte_receiver.try_recv() {
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        disconnected = true;
+                        session.send_close_notify();
+                    }
+                    Ok(bytes) => {
+                        if let Err(error) = session.write_all(&bytes) {
+                            println!("Error encrypting data: {:?}", error);
+                            return;
+                        }
+                    }
+                }
+            }
+
+            while session.wants_write() {
+                if let Err(error) = session.write_tls(&mut socket) {
+                    println!("Error writing to socket: {:?}", error);
+                    return;
                 }
+            }
+
+            // Everything queued before the connection was closed got written
+            if disconnected {
+                return;
+            }
 
+            match session.read_tls(&mut socket) {
+                Ok(0) => return, // socket closed
+                Ok(_) => (),
+                Err(ref error)
+                    if error.kind() == io::ErrorKind::WouldBlock
+                        || error.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue
+                }
                 Err(error) => {
                     println!("Error occurred reading from socket: {:?}", error);
                     return;
                 }
             }
+
+            if let Err(error) = session.process_new_packets() {
+                println!("TLS error: {:?}", error);
+                // Let the peer know about the alert
+                let _ = session.write_tls(&mut socket);
+                return;
+            }
+
+            loop {
+                let read_count = match session.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(read_count) => read_count,
+                    Err(error) => {
+                        println!("Error decrypting data: {:?}", error);
+                        return;
+                    }
+                };
+
+                let mut send_buffer = [0; BUFFER_SIZE];
+                send_buffer[..read_count].copy_from_slice(&buffer[..read_count]);
+                let result = ReadResult::BytesReceived {
+                    buffer: send_buffer,
+                    byte_count: read_count,
+                };
+
+                if sender.send(result).is_err() {
+                    return; // connection closed
+                }
+            }
         }
-    });
+    })
 }
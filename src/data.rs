@@ -1,18 +1,109 @@
+use crate::flv::{VideoPacket, VideoPacketType};
 use bytes::Bytes;
+use rml_amf0::Amf0Value;
 use rml_rtmp::sessions::StreamMetadata;
+use std::fmt;
+
+/// Published streams are identified by the application name and the stream key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChannelKey {
+    pub app_name: String,
+    pub stream_key: String,
+}
+
+impl fmt::Display for ChannelKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.app_name, self.stream_key)
+    }
+}
 
 pub enum RtmpInput {
-    Media(Media),
-    Metadata(StreamMetadata),
+    PublishStarted(ChannelKey),
+    Media(ChannelKey, Media),
+    Metadata(ChannelKey, StreamMetadata),
+    /// A data message of the publisher, carrying every property of onMetaData unlike `Metadata`
+    Data(ChannelKey, DataMessage),
+    PublishEnded(ChannelKey),
+    /// The state of the forwarding of a channel to a push target changed
+    PushStatus(ChannelKey, String, PushStatus),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushStatus {
+    Connecting,
+    Publishing,
+    /// The connection failed or got closed, with the reason, it is retried after a while
+    Disconnected(String),
+}
+
+impl PushStatus {
+    pub fn nick(&self) -> &'static str {
+        match self {
+            PushStatus::Connecting => "connecting",
+            PushStatus::Publishing => "publishing",
+            PushStatus::Disconnected(_) => "disconnected",
+        }
+    }
+}
+
+/// AMF0 data message, such as onMetaData or onCuePoint, without its @setDataFrame prefix
+#[derive(Debug, Clone)]
+pub struct DataMessage {
+    pub name: String,
+    pub values: Vec<Amf0Value>,
+    /// The message as received, AMF0 encoded
+    pub payload: Bytes,
+    pub timestamp: u32,
+}
+
+impl DataMessage {
+    pub fn new(mut values: Vec<Amf0Value>, payload: Bytes, timestamp: u32) -> Option<DataMessage> {
+        if let Some(Amf0Value::Utf8String(name)) = values.first() {
+            if name == "@setDataFrame" {
+                values.remove(0);
+            }
+        }
+
+        if values.is_empty() {
+            return None;
+        }
+
+        match values.remove(0) {
+            Amf0Value::Utf8String(name) => Some(DataMessage {
+                name,
+                values,
+                payload,
+                timestamp,
+            }),
+            _ => None,
+        }
+    }
 }
 
 pub struct Media {
     pub media_type: MediaType,
     pub data: Bytes,
     pub timestamp: u32,
+    /// Cleared for the sequence headers, which the decoders can not do without
     pub can_be_dropped: bool,
 }
 
+impl Media {
+    /// Video keyframe, the sequence headers are not counted as keyframes
+    pub fn is_keyframe(&self) -> bool {
+        match self.media_type {
+            MediaType::Video => match VideoPacket::parse(&self.data) {
+                Some(packet) => {
+                    packet.packet_type == VideoPacketType::CodedFrames && packet.is_keyframe
+                }
+                None => false,
+            },
+            MediaType::Audio => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MediaType {
     Video,
     Audio,
@@ -0,0 +1,896 @@
+// Helpers to interpret the FLV tag bodies carried inside RTMP audio and video messages.
+// See the "Video tags" and "Audio tags" sections of the FLV specification (v10.1, Annex E), and
+// the Enhanced RTMP specification (v2) for the extended video and audio tag headers.
+use bytes::{Buf, Bytes, BytesMut};
+use rml_amf0::Amf0Value;
+use rml_rtmp::sessions::StreamMetadata;
+use std::collections::HashMap;
+
+pub const TAG_TYPE_AUDIO: u8 = 8;
+pub const TAG_TYPE_VIDEO: u8 = 9;
+pub const TAG_TYPE_SCRIPT_DATA: u8 = 18;
+
+const FILE_HEADER_SIZE: usize = 9;
+const TAG_HEADER_SIZE: u32 = 11;
+
+const VIDEO_CODEC_SORENSON_H263: u8 = 2;
+const VIDEO_CODEC_SCREEN_VIDEO: u8 = 3;
+const VIDEO_CODEC_VP6: u8 = 4;
+const VIDEO_CODEC_VP6_ALPHA: u8 = 5;
+const VIDEO_CODEC_SCREEN_VIDEO_2: u8 = 6;
+const VIDEO_CODEC_AVC: u8 = 7;
+const FRAME_TYPE_KEYFRAME: u8 = 1;
+const FRAME_TYPE_COMMAND: u8 = 5;
+
+// Packet types of the extended video and audio tag headers
+const EX_PACKET_TYPE_SEQUENCE_START: u8 = 0;
+const EX_PACKET_TYPE_CODED_FRAMES: u8 = 1;
+const EX_PACKET_TYPE_SEQUENCE_END: u8 = 2;
+const EX_PACKET_TYPE_CODED_FRAMES_X: u8 = 3;
+const EX_PACKET_TYPE_MPEG2TS_SEQUENCE_START: u8 = 5;
+
+const AUDIO_FORMAT_PCM_PLATFORM_ENDIAN: u8 = 0;
+const AUDIO_FORMAT_ADPCM: u8 = 1;
+const AUDIO_FORMAT_MP3: u8 = 2;
+const AUDIO_FORMAT_PCM_LITTLE_ENDIAN: u8 = 3;
+const AUDIO_FORMAT_NELLYMOSER_16KHZ_MONO: u8 = 4;
+const AUDIO_FORMAT_NELLYMOSER_8KHZ_MONO: u8 = 5;
+const AUDIO_FORMAT_NELLYMOSER: u8 = 6;
+const AUDIO_FORMAT_G711_A_LAW: u8 = 7;
+const AUDIO_FORMAT_G711_MU_LAW: u8 = 8;
+const AUDIO_FORMAT_EX_HEADER: u8 = 9;
+const AUDIO_FORMAT_AAC: u8 = 10;
+const AUDIO_FORMAT_SPEEX: u8 = 11;
+const AUDIO_FORMAT_MP3_8KHZ: u8 = 14;
+const EX_AUDIO_PACKET_TYPE_MULTICHANNEL_CONFIG: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VideoCodec {
+    SorensonH263,
+    ScreenVideo,
+    Vp6,
+    Vp6Alpha,
+    ScreenVideo2,
+    Avc,
+    Hevc,
+    Av1,
+    Vp9,
+}
+
+impl VideoCodec {
+    /// CodecID of the legacy video tag header, AVC being handled on its own
+    fn from_codec_id(codec_id: u8) -> Option<VideoCodec> {
+        match codec_id {
+            VIDEO_CODEC_SORENSON_H263 => Some(VideoCodec::SorensonH263),
+            VIDEO_CODEC_SCREEN_VIDEO => Some(VideoCodec::ScreenVideo),
+            VIDEO_CODEC_VP6 => Some(VideoCodec::Vp6),
+            VIDEO_CODEC_VP6_ALPHA => Some(VideoCodec::Vp6Alpha),
+            VIDEO_CODEC_SCREEN_VIDEO_2 => Some(VideoCodec::ScreenVideo2),
+            _ => None,
+        }
+    }
+
+    /// FourCC identifying the codec in the extended video tag header of Enhanced RTMP
+    fn from_fourcc(fourcc: &[u8]) -> Option<VideoCodec> {
+        match fourcc {
+            b"avc1" => Some(VideoCodec::Avc),
+            b"hvc1" => Some(VideoCodec::Hevc),
+            b"av01" => Some(VideoCodec::Av1),
+            b"vp09" => Some(VideoCodec::Vp9),
+            _ => None,
+        }
+    }
+
+    pub fn fourcc(&self) -> Option<&'static str> {
+        match self {
+            VideoCodec::Avc => Some("avc1"),
+            VideoCodec::Hevc => Some("hvc1"),
+            VideoCodec::Av1 => Some("av01"),
+            VideoCodec::Vp9 => Some("vp09"),
+            _ => None,
+        }
+    }
+
+    /// Whether the frames can not be decoded without the configuration of the sequence header
+    pub fn requires_sequence_header(&self) -> bool {
+        matches!(self, VideoCodec::Avc | VideoCodec::Hevc | VideoCodec::Av1)
+    }
+}
+
+/// Video codecs advertised to the clients supporting Enhanced RTMP
+pub const ENHANCED_VIDEO_CODECS: [VideoCodec; 4] = [
+    VideoCodec::Avc,
+    VideoCodec::Hevc,
+    VideoCodec::Av1,
+    VideoCodec::Vp9,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoPacketType {
+    /// The decoder configuration record of the codec
+    SequenceHeader,
+    CodedFrames,
+    EndOfSequence,
+    /// The AV1 video descriptor of MPEG-2 TS, which does not replace the configuration record
+    Mpeg2TsSequenceStart,
+}
+
+/// Body of a video tag, either a legacy tag or an Enhanced RTMP one
+pub struct VideoPacket {
+    pub codec: VideoCodec,
+    pub packet_type: VideoPacketType,
+    pub is_keyframe: bool,
+    /// Offset in milliseconds between the presentation and decoding timestamps
+    pub composition_time: i32,
+    /// Either the decoder configuration record or the coded frames
+    pub data: Bytes,
+}
+
+impl VideoPacket {
+    pub fn parse(data: &Bytes) -> Option<VideoPacket> {
+        if data.is_empty() {
+            return None;
+        }
+
+        if data[0] & 0x80 != 0 {
+            return VideoPacket::parse_extended(data);
+        }
+
+        let frame_type = (data[0] >> 4) & 0x07;
+        if data[0] & 0x0f != VIDEO_CODEC_AVC {
+            return VideoPacket::parse_legacy(data, frame_type);
+        }
+
+        if data.len() < 5 {
+            return None;
+        }
+
+        let packet_type = match data[1] {
+            0 => VideoPacketType::SequenceHeader,
+            1 => VideoPacketType::CodedFrames,
+            2 => VideoPacketType::EndOfSequence,
+            _ => return None,
+        };
+
+        Some(VideoPacket {
+            codec: VideoCodec::Avc,
+            packet_type,
+            is_keyframe: frame_type == FRAME_TYPE_KEYFRAME,
+            composition_time: composition_time(&data[2..5]),
+            data: data.slice(5..),
+        })
+    }
+
+    /// The codecs preceding AVC have no packet type, every tag carries a frame
+    fn parse_legacy(data: &Bytes, frame_type: u8) -> Option<VideoPacket> {
+        let codec = VideoCodec::from_codec_id(data[0] & 0x0f)?;
+        if frame_type == FRAME_TYPE_COMMAND {
+            return None;
+        }
+
+        // VP6 frames start with the dimensions adjustment, which the decoders do not expect
+        let header_size = match codec {
+            VideoCodec::Vp6 | VideoCodec::Vp6Alpha => 2,
+            _ => 1,
+        };
+        if data.len() <= header_size {
+            return None;
+        }
+
+        Some(VideoPacket {
+            codec,
+            packet_type: VideoPacketType::CodedFrames,
+            is_keyframe: frame_type == FRAME_TYPE_KEYFRAME,
+            composition_time: 0,
+            data: data.slice(header_size..),
+        })
+    }
+
+    /// Parses the ExVideoTagHeader: IsExHeader bit, FrameType, PacketType then the FourCC
+    fn parse_extended(data: &Bytes) -> Option<VideoPacket> {
+        if data.len() < 5 {
+            return None;
+        }
+
+        let frame_type = (data[0] >> 4) & 0x07;
+        let codec = VideoCodec::from_fourcc(&data[1..5])?;
+
+        let (packet_type, header_size, composition) = match data[0] & 0x0f {
+            EX_PACKET_TYPE_SEQUENCE_START => (VideoPacketType::SequenceHeader, 5, 0),
+            EX_PACKET_TYPE_CODED_FRAMES => match codec {
+                // Only the codecs with B-frames carry a composition time
+                VideoCodec::Avc | VideoCodec::Hevc => {
+                    if data.len() < 8 {
+                        return None;
+                    }
+                    (
+                        VideoPacketType::CodedFrames,
+                        8,
+                        composition_time(&data[5..8]),
+                    )
+                }
+                _ => (VideoPacketType::CodedFrames, 5, 0),
+            },
+            EX_PACKET_TYPE_CODED_FRAMES_X => (VideoPacketType::CodedFrames, 5, 0),
+            EX_PACKET_TYPE_SEQUENCE_END => (VideoPacketType::EndOfSequence, 5, 0),
+            EX_PACKET_TYPE_MPEG2TS_SEQUENCE_START => (VideoPacketType::Mpeg2TsSequenceStart, 5, 0),
+            _ => return None,
+        };
+
+        // Command frames carry no video
+        if frame_type == FRAME_TYPE_COMMAND && packet_type == VideoPacketType::CodedFrames {
+            return None;
+        }
+
+        Some(VideoPacket {
+            codec,
+            packet_type,
+            is_keyframe: frame_type == FRAME_TYPE_KEYFRAME,
+            composition_time: composition,
+            data: data.slice(header_size..),
+        })
+    }
+}
+
+/// Signed 24 bits integer, sign extended by shifting it to the top of the i32
+fn composition_time(data: &[u8]) -> i32 {
+    ((data[0] as i32) << 24 | (data[1] as i32) << 16 | (data[2] as i32) << 8) >> 8
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AudioCodec {
+    /// Interleaved little endian samples of the given width in bits
+    Pcm(u8),
+    Adpcm,
+    Nellymoser,
+    ALaw,
+    MuLaw,
+    Speex,
+    Aac,
+    Opus,
+    Flac,
+    Ac3,
+    Eac3,
+    Mp3,
+}
+
+impl AudioCodec {
+    /// FourCC identifying the codec in the extended audio tag header of Enhanced RTMP
+    fn from_fourcc(fourcc: &[u8]) -> Option<AudioCodec> {
+        match fourcc {
+            b"mp4a" => Some(AudioCodec::Aac),
+            b"Opus" => Some(AudioCodec::Opus),
+            b"fLaC" => Some(AudioCodec::Flac),
+            b"ac-3" => Some(AudioCodec::Ac3),
+            b"ec-3" => Some(AudioCodec::Eac3),
+            b".mp3" => Some(AudioCodec::Mp3),
+            _ => None,
+        }
+    }
+
+    /// Whether the frames can not be decoded without the configuration of the sequence start
+    pub fn requires_sequence_header(&self) -> bool {
+        matches!(self, AudioCodec::Aac | AudioCodec::Flac)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioPacketType {
+    /// The decoder configuration of the codec
+    SequenceHeader,
+    CodedFrames,
+    EndOfSequence,
+    /// The channel count and layout of the Enhanced RTMP stream
+    MultichannelConfig,
+}
+
+/// Body of an audio tag, either a legacy tag or an Enhanced RTMP one
+pub struct AudioPacket {
+    pub codec: AudioCodec,
+    pub packet_type: AudioPacketType,
+    /// Sample rate announced by the legacy tag header, it does not apply to AAC
+    pub rate: Option<u32>,
+    /// Number of channels announced by the legacy tag header or a multichannel configuration
+    pub channels: Option<u8>,
+    /// Either the decoder configuration, the channel layout or the coded frames
+    pub data: Bytes,
+}
+
+impl AudioPacket {
+    pub fn parse(data: &Bytes) -> Option<AudioPacket> {
+        if data.is_empty() {
+            return None;
+        }
+
+        if data[0] >> 4 == AUDIO_FORMAT_EX_HEADER {
+            return AudioPacket::parse_extended(data);
+        }
+
+        if data[0] >> 4 != AUDIO_FORMAT_AAC {
+            return AudioPacket::parse_legacy(data);
+        }
+
+        if data.len() < 2 {
+            return None;
+        }
+
+        let packet_type = match data[1] {
+            0 => AudioPacketType::SequenceHeader,
+            1 => AudioPacketType::CodedFrames,
+            _ => return None,
+        };
+
+        Some(AudioPacket {
+            codec: AudioCodec::Aac,
+            packet_type,
+            rate: None,
+            channels: None,
+            data: data.slice(2..),
+        })
+    }
+
+    /// The codecs other than AAC have no packet type, their format is described by the
+    /// SoundRate, SoundSize and SoundType bits of the header
+    fn parse_legacy(data: &Bytes) -> Option<AudioPacket> {
+        if data.len() < 2 {
+            return None;
+        }
+
+        let is_16_bits = data[0] & 0x02 != 0;
+        let codec = match data[0] >> 4 {
+            AUDIO_FORMAT_PCM_PLATFORM_ENDIAN | AUDIO_FORMAT_PCM_LITTLE_ENDIAN => {
+                AudioCodec::Pcm(if is_16_bits { 16 } else { 8 })
+            }
+            AUDIO_FORMAT_ADPCM => AudioCodec::Adpcm,
+            AUDIO_FORMAT_MP3 | AUDIO_FORMAT_MP3_8KHZ => AudioCodec::Mp3,
+            AUDIO_FORMAT_NELLYMOSER_16KHZ_MONO
+            | AUDIO_FORMAT_NELLYMOSER_8KHZ_MONO
+            | AUDIO_FORMAT_NELLYMOSER => AudioCodec::Nellymoser,
+            AUDIO_FORMAT_G711_A_LAW => AudioCodec::ALaw,
+            AUDIO_FORMAT_G711_MU_LAW => AudioCodec::MuLaw,
+            AUDIO_FORMAT_SPEEX => AudioCodec::Speex,
+            _ => return None,
+        };
+
+        // Some formats imply a rate and channels regardless of the bits of the header
+        let (rate, channels) = match data[0] >> 4 {
+            AUDIO_FORMAT_NELLYMOSER_16KHZ_MONO | AUDIO_FORMAT_SPEEX => (16_000, 1),
+            AUDIO_FORMAT_NELLYMOSER_8KHZ_MONO | AUDIO_FORMAT_MP3_8KHZ => (8_000, 1),
+            AUDIO_FORMAT_G711_A_LAW | AUDIO_FORMAT_G711_MU_LAW => (8_000, 1),
+            _ => {
+                let rate = match (data[0] >> 2) & 0x03 {
+                    0 => 5_512,
+                    1 => 11_025,
+                    2 => 22_050,
+                    _ => 44_100,
+                };
+                (rate, if data[0] & 0x01 != 0 { 2 } else { 1 })
+            }
+        };
+
+        Some(AudioPacket {
+            codec,
+            packet_type: AudioPacketType::CodedFrames,
+            rate: Some(rate),
+            channels: Some(channels),
+            data: data.slice(1..),
+        })
+    }
+
+    /// Parses the ExAudioTagHeader: SoundFormat 9, AudioPacketType then the FourCC
+    fn parse_extended(data: &Bytes) -> Option<AudioPacket> {
+        if data.len() < 5 {
+            return None;
+        }
+
+        let codec = AudioCodec::from_fourcc(&data[1..5])?;
+        let mut channels = None;
+        let packet_type = match data[0] & 0x0f {
+            EX_PACKET_TYPE_SEQUENCE_START => AudioPacketType::SequenceHeader,
+            EX_PACKET_TYPE_CODED_FRAMES => AudioPacketType::CodedFrames,
+            EX_PACKET_TYPE_SEQUENCE_END => AudioPacketType::EndOfSequence,
+            EX_AUDIO_PACKET_TYPE_MULTICHANNEL_CONFIG => {
+                // The channel order, then the channel count and the layout it describes
+                if data.len() < 7 {
+                    return None;
+                }
+                channels = Some(data[6]);
+                AudioPacketType::MultichannelConfig
+            }
+            // Multitrack and ModEx packets are not supported
+            _ => return None,
+        };
+
+        Some(AudioPacket {
+            codec,
+            packet_type,
+            rate: None,
+            channels,
+            data: data.slice(5..),
+        })
+    }
+}
+
+/// Video tag body carrying the AVCDecoderConfigurationRecord
+pub fn avc_sequence_header(codec_data: &[u8]) -> Vec<u8> {
+    let mut body = vec![0x10 | VIDEO_CODEC_AVC, 0, 0, 0, 0];
+    body.extend_from_slice(codec_data);
+    body
+}
+
+/// Video tag body carrying length prefixed NAL units
+pub fn avc_nalu(data: &[u8], is_keyframe: bool, composition_time: i32) -> Vec<u8> {
+    let frame_type = if is_keyframe { 0x10 } else { 0x20 };
+    let mut body = Vec::with_capacity(data.len() + 5);
+    body.push(frame_type | VIDEO_CODEC_AVC);
+    body.push(1);
+    body.extend_from_slice(&composition_time.to_be_bytes()[1..]);
+    body.extend_from_slice(data);
+    body
+}
+
+/// Audio tag body carrying the AudioSpecificConfig
+pub fn aac_sequence_header(codec_data: &[u8]) -> Vec<u8> {
+    aac_body(0, codec_data)
+}
+
+/// Audio tag body carrying a raw AAC frame
+pub fn aac_raw(data: &[u8]) -> Vec<u8> {
+    aac_body(1, data)
+}
+
+fn aac_body(packet_type: u8, data: &[u8]) -> Vec<u8> {
+    // The rate, size and channel bits are ignored for AAC, the spec asks for 44kHz 16 bits stereo
+    let mut body = Vec::with_capacity(data.len() + 2);
+    body.push(AUDIO_FORMAT_AAC << 4 | 0x0f);
+    body.push(packet_type);
+    body.extend_from_slice(data);
+    body
+}
+
+/// A tag read from a FLV byte stream
+pub struct Tag {
+    pub tag_type: u8,
+    pub timestamp: u32,
+    pub body: Bytes,
+}
+
+/// Splits a FLV byte stream into tags
+#[derive(Default)]
+pub struct TagReader {
+    pending: BytesMut,
+    has_read_header: bool,
+}
+
+impl TagReader {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+    }
+
+    /// Returns the next complete tag, or an error if the stream is not FLV
+    pub fn next_tag(&mut self) -> Result<Option<Tag>, String> {
+        if !self.has_read_header {
+            if self.pending.len() < FILE_HEADER_SIZE + 4 {
+                return Ok(None);
+            }
+            if &self.pending[..3] != b"FLV" {
+                return Err("Missing FLV signature".to_string());
+            }
+
+            let header_size = u32::from_be_bytes([
+                self.pending[5],
+                self.pending[6],
+                self.pending[7],
+                self.pending[8],
+            ]) as usize;
+            if self.pending.len() < header_size + 4 {
+                return Ok(None);
+            }
+            self.pending.advance(header_size + 4);
+            self.has_read_header = true;
+        }
+
+        if self.pending.len() < TAG_HEADER_SIZE as usize {
+            return Ok(None);
+        }
+
+        let header = &self.pending[..TAG_HEADER_SIZE as usize];
+        let tag_type = header[0] & 0x1f;
+        let data_size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let timestamp = u32::from_be_bytes([header[7], header[4], header[5], header[6]]);
+
+        let tag_size = TAG_HEADER_SIZE as usize + data_size;
+        if self.pending.len() < tag_size + 4 {
+            return Ok(None);
+        }
+
+        let mut tag = self.pending.split_to(tag_size + 4);
+        tag.advance(TAG_HEADER_SIZE as usize);
+        tag.truncate(data_size);
+
+        Ok(Some(Tag {
+            tag_type,
+            timestamp,
+            body: tag.freeze(),
+        }))
+    }
+}
+
+/// FLV file header followed by the first (always zero) PreviousTagSize field
+pub fn file_header(has_audio: bool, has_video: bool) -> Vec<u8> {
+    let mut flags = 0;
+    if has_audio {
+        flags |= 0x04;
+    }
+    if has_video {
+        flags |= 0x01;
+    }
+
+    vec![b'F', b'L', b'V', 1, flags, 0, 0, 0, 9, 0, 0, 0, 0]
+}
+
+/// Wraps a tag body into a FLV tag followed by its PreviousTagSize field
+pub fn tag(tag_type: u8, timestamp: u32, body: &[u8]) -> Vec<u8> {
+    let data_size = body.len() as u32;
+    let mut tag = Vec::with_capacity((TAG_HEADER_SIZE + data_size) as usize + 4);
+
+    tag.push(tag_type);
+    tag.extend_from_slice(&data_size.to_be_bytes()[1..]);
+    // Lower 24 bits of the timestamp followed by the extended upper 8 bits
+    tag.extend_from_slice(&timestamp.to_be_bytes()[1..]);
+    tag.push((timestamp >> 24) as u8);
+    // StreamID, always 0
+    tag.extend_from_slice(&[0, 0, 0]);
+    tag.extend_from_slice(body);
+    tag.extend_from_slice(&(TAG_HEADER_SIZE + data_size).to_be_bytes());
+
+    tag
+}
+
+/// Serializes the stream metadata back into the body of an onMetaData script data tag
+pub fn metadata_tag_body(metadata: &StreamMetadata) -> Result<Vec<u8>, String> {
+    let mut properties = HashMap::new();
+
+    if let Some(val) = metadata.video_width {
+        properties.insert("width".to_string(), Amf0Value::Number(val as f64));
+    }
+    if let Some(val) = metadata.video_height {
+        properties.insert("height".to_string(), Amf0Value::Number(val as f64));
+    }
+    if let Some(ref val) = metadata.video_codec {
+        properties.insert("videocodecid".to_string(), codec_id_value(val));
+    }
+    if let Some(val) = metadata.video_frame_rate {
+        properties.insert("framerate".to_string(), Amf0Value::Number(val as f64));
+    }
+    if let Some(val) = metadata.video_bitrate_kbps {
+        properties.insert("videodatarate".to_string(), Amf0Value::Number(val as f64));
+    }
+    if let Some(ref val) = metadata.audio_codec {
+        properties.insert("audiocodecid".to_string(), codec_id_value(val));
+    }
+    if let Some(val) = metadata.audio_bitrate_kbps {
+        properties.insert("audiodatarate".to_string(), Amf0Value::Number(val as f64));
+    }
+    if let Some(val) = metadata.audio_sample_rate {
+        properties.insert("audiosamplerate".to_string(), Amf0Value::Number(val as f64));
+    }
+    if let Some(val) = metadata.audio_channels {
+        properties.insert("audiochannels".to_string(), Amf0Value::Number(val as f64));
+    }
+    if let Some(val) = metadata.audio_is_stereo {
+        properties.insert("stereo".to_string(), Amf0Value::Boolean(val));
+    }
+    if let Some(ref val) = metadata.encoder {
+        properties.insert("encoder".to_string(), Amf0Value::Utf8String(val.clone()));
+    }
+
+    let values = vec![
+        Amf0Value::Utf8String("onMetaData".to_string()),
+        Amf0Value::Object(properties),
+    ];
+    rml_amf0::serialize(&values).map_err(|error| format!("{:?}", error))
+}
+
+/// Reads the stream metadata from the body of an onMetaData script data tag
+pub fn parse_metadata_tag_body(body: &[u8]) -> Option<StreamMetadata> {
+    let mut values = rml_amf0::deserialize(&mut &body[..]).ok()?.into_iter();
+    match values.next() {
+        Some(Amf0Value::Utf8String(ref name)) if name == "onMetaData" => (),
+        // Written by the RTMP clients, which wrap the metadata in a @setDataFrame call
+        Some(Amf0Value::Utf8String(ref name)) if name == "@setDataFrame" => {
+            values.next();
+        }
+        _ => return None,
+    }
+
+    let properties = match values.next() {
+        Some(Amf0Value::Object(properties)) => properties,
+        _ => return None,
+    };
+
+    let number = |name: &str| match properties.get(name) {
+        Some(Amf0Value::Number(value)) => Some(*value),
+        _ => None,
+    };
+    let text = |name: &str| match properties.get(name) {
+        Some(Amf0Value::Number(value)) => Some(value.to_string()),
+        Some(Amf0Value::Utf8String(value)) => Some(value.clone()),
+        _ => None,
+    };
+
+    Some(StreamMetadata {
+        video_width: number("width").map(|value| value as u32),
+        video_height: number("height").map(|value| value as u32),
+        video_codec: text("videocodecid"),
+        video_frame_rate: number("framerate").map(|value| value as f32),
+        video_bitrate_kbps: number("videodatarate").map(|value| value as u32),
+        audio_codec: text("audiocodecid"),
+        audio_bitrate_kbps: number("audiodatarate").map(|value| value as u32),
+        audio_sample_rate: number("audiosamplerate").map(|value| value as u32),
+        audio_channels: number("audiochannels").map(|value| value as u32),
+        audio_is_stereo: match properties.get("stereo") {
+            Some(Amf0Value::Boolean(value)) => Some(*value),
+            _ => None,
+        },
+        encoder: match properties.get("encoder") {
+            Some(Amf0Value::Utf8String(value)) => Some(value.clone()),
+            _ => None,
+        },
+    })
+}
+
+/// Metadata without any property, the constructor of the session module being private
+pub fn empty_metadata() -> StreamMetadata {
+    StreamMetadata {
+        video_width: None,
+        video_height: None,
+        video_codec: None,
+        video_frame_rate: None,
+        video_bitrate_kbps: None,
+        audio_codec: None,
+        audio_bitrate_kbps: None,
+        audio_sample_rate: None,
+        audio_channels: None,
+        audio_is_stereo: None,
+        encoder: None,
+    }
+}
+
+/// Codec ids are usually numeric, but some encoders send FourCCs like "avc1"
+fn codec_id_value(codec: &str) -> Amf0Value {
+    match codec.parse::<f64>() {
+        Ok(id) => Amf0Value::Number(id),
+        Err(_) => Amf0Value::Utf8String(codec.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(data: &[u8]) -> Option<VideoPacket> {
+        VideoPacket::parse(&Bytes::copy_from_slice(data))
+    }
+
+    #[test]
+    fn parses_avc_tags() {
+        let packet = video(&[0x17, 0, 0, 0, 0, 1, 0x64]).unwrap();
+        assert_eq!(packet.codec, VideoCodec::Avc);
+        assert_eq!(packet.packet_type, VideoPacketType::SequenceHeader);
+        assert_eq!(&packet.data[..], &[1, 0x64]);
+
+        // Composition time of -33ms
+        let packet = video(&[0x27, 1, 0xff, 0xff, 0xdf, 0, 0, 0, 1]).unwrap();
+        assert_eq!(packet.packet_type, VideoPacketType::CodedFrames);
+        assert!(!packet.is_keyframe);
+        assert_eq!(packet.composition_time, -33);
+        assert_eq!(&packet.data[..], &[0, 0, 0, 1]);
+
+        let packet = video(&[0x17, 2, 0, 0, 0]).unwrap();
+        assert_eq!(packet.packet_type, VideoPacketType::EndOfSequence);
+
+        assert!(video(&[0x17, 1, 0, 0]).is_none());
+        assert!(video(&[0x17, 3, 0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn parses_enhanced_video_tags() {
+        let packet = video(b"\x90hvc1\x01\x02").unwrap();
+        assert_eq!(packet.codec, VideoCodec::Hevc);
+        assert_eq!(packet.packet_type, VideoPacketType::SequenceHeader);
+        assert_eq!(&packet.data[..], &[1, 2]);
+
+        // HEVC frames carry a composition time, unless sent as CodedFramesX
+        let packet = video(b"\x91hvc1\x00\x00\x28\x07").unwrap();
+        assert_eq!(packet.packet_type, VideoPacketType::CodedFrames);
+        assert!(packet.is_keyframe);
+        assert_eq!(packet.composition_time, 40);
+        assert_eq!(&packet.data[..], &[7]);
+
+        let packet = video(b"\xa3hvc1\x07").unwrap();
+        assert_eq!(packet.packet_type, VideoPacketType::CodedFrames);
+        assert!(!packet.is_keyframe);
+        assert_eq!(packet.composition_time, 0);
+        assert_eq!(&packet.data[..], &[7]);
+
+        // AV1 and VP9 frames have no composition time
+        let packet = video(b"\x91av01\x12\x00").unwrap();
+        assert_eq!(packet.codec, VideoCodec::Av1);
+        assert!(packet.is_keyframe);
+        assert_eq!(&packet.data[..], &[0x12, 0]);
+
+        let packet = video(b"\xa1vp09\x01").unwrap();
+        assert_eq!(packet.codec, VideoCodec::Vp9);
+        assert!(!packet.is_keyframe);
+
+        let packet = video(b"\x92vp09").unwrap();
+        assert_eq!(packet.packet_type, VideoPacketType::EndOfSequence);
+    }
+
+    #[test]
+    fn keeps_mpeg2ts_sequence_start_apart() {
+        let packet = video(b"\x95av01\x80\x04").unwrap();
+        assert_eq!(packet.codec, VideoCodec::Av1);
+        assert_eq!(packet.packet_type, VideoPacketType::Mpeg2TsSequenceStart);
+        assert_eq!(&packet.data[..], &[0x80, 4]);
+    }
+
+    #[test]
+    fn rejects_unsupported_enhanced_video_tags() {
+        // Unknown FourCC, truncated header, command frame and unsupported packet type
+        assert!(video(b"\x90avc3\x01").is_none());
+        assert!(video(b"\x90hvc").is_none());
+        assert!(video(b"\x91hvc1\x00\x00").is_none());
+        assert!(video(b"\xd1av01\x00").is_none());
+        assert!(video(b"\x96av01\x00").is_none());
+    }
+
+    fn audio(data: &[u8]) -> Option<AudioPacket> {
+        AudioPacket::parse(&Bytes::copy_from_slice(data))
+    }
+
+    #[test]
+    fn parses_aac_tags() {
+        let packet = audio(&[0xaf, 0, 0x12, 0x10]).unwrap();
+        assert_eq!(packet.codec, AudioCodec::Aac);
+        assert_eq!(packet.packet_type, AudioPacketType::SequenceHeader);
+        assert_eq!(packet.rate, None);
+        assert_eq!(&packet.data[..], &[0x12, 0x10]);
+
+        let packet = audio(&[0xaf, 1, 0x21]).unwrap();
+        assert_eq!(packet.packet_type, AudioPacketType::CodedFrames);
+        assert_eq!(&packet.data[..], &[0x21]);
+
+        assert!(audio(&[0xaf]).is_none());
+        assert!(audio(&[0xaf, 2]).is_none());
+    }
+
+    #[test]
+    fn parses_enhanced_audio_tags() {
+        let packet = audio(b"\x90Opus\x01\x02").unwrap();
+        assert_eq!(packet.codec, AudioCodec::Opus);
+        assert_eq!(packet.packet_type, AudioPacketType::SequenceHeader);
+        assert_eq!(&packet.data[..], &[1, 2]);
+
+        let packet = audio(b"\x91fLaC\xff\xf8").unwrap();
+        assert_eq!(packet.codec, AudioCodec::Flac);
+        assert_eq!(packet.packet_type, AudioPacketType::CodedFrames);
+        assert_eq!(&packet.data[..], &[0xff, 0xf8]);
+
+        let packet = audio(b"\x91ac-3\x0b\x77").unwrap();
+        assert_eq!(packet.codec, AudioCodec::Ac3);
+
+        let packet = audio(b"\x92ec-3").unwrap();
+        assert_eq!(packet.codec, AudioCodec::Eac3);
+        assert_eq!(packet.packet_type, AudioPacketType::EndOfSequence);
+
+        let packet = audio(b"\x91.mp3\xff").unwrap();
+        assert_eq!(packet.codec, AudioCodec::Mp3);
+        assert_eq!(packet.rate, None);
+    }
+
+    #[test]
+    fn parses_multichannel_config() {
+        // Native channel order, 6 channels
+        let packet = audio(b"\x94Opus\x01\x06\x00\x00\x00\x3f").unwrap();
+        assert_eq!(packet.packet_type, AudioPacketType::MultichannelConfig);
+        assert_eq!(packet.channels, Some(6));
+
+        assert!(audio(b"\x94Opus\x01").is_none());
+    }
+
+    #[test]
+    fn rejects_unsupported_enhanced_audio_tags() {
+        // Unknown FourCC, truncated header, Multitrack and ModEx packets
+        assert!(audio(b"\x91vrbs\x00").is_none());
+        assert!(audio(b"\x91Opu").is_none());
+        assert!(audio(b"\x95Opus\x00").is_none());
+        assert!(audio(b"\x97Opus\x00").is_none());
+    }
+
+    #[test]
+    fn parses_legacy_video_tags() {
+        let packet = video(&[0x12, 0, 0x84]).unwrap();
+        assert_eq!(packet.codec, VideoCodec::SorensonH263);
+        assert_eq!(packet.packet_type, VideoPacketType::CodedFrames);
+        assert!(packet.is_keyframe);
+        assert_eq!(&packet.data[..], &[0, 0x84]);
+
+        let packet = video(&[0x23, 0x01]).unwrap();
+        assert_eq!(packet.codec, VideoCodec::ScreenVideo);
+        assert!(!packet.is_keyframe);
+
+        // The dimensions adjustment of VP6 is skipped
+        let packet = video(&[0x14, 0x00, 0x78, 0x46]).unwrap();
+        assert_eq!(packet.codec, VideoCodec::Vp6);
+        assert_eq!(&packet.data[..], &[0x78, 0x46]);
+        assert!(video(&[0x14, 0x00]).is_none());
+
+        // Command frames, empty tags and unknown codecs
+        assert!(video(&[0x52, 0x00]).is_none());
+        assert!(video(&[0x12]).is_none());
+        assert!(video(&[0x19, 0x00]).is_none());
+    }
+
+    #[test]
+    fn parses_legacy_audio_tags() {
+        let packet = audio(&[0x2f, 0xff]).unwrap();
+        assert_eq!(packet.codec, AudioCodec::Mp3);
+        assert_eq!(packet.packet_type, AudioPacketType::CodedFrames);
+        assert_eq!(packet.rate, Some(44_100));
+        assert_eq!(packet.channels, Some(2));
+        assert_eq!(&packet.data[..], &[0xff]);
+
+        let packet = audio(&[0x3e, 0]).unwrap();
+        assert_eq!(packet.codec, AudioCodec::Pcm(16));
+        assert_eq!(packet.channels, Some(1));
+        let packet = audio(&[0x05, 0]).unwrap();
+        assert_eq!(packet.codec, AudioCodec::Pcm(8));
+        assert_eq!(packet.rate, Some(11_025));
+        assert_eq!(packet.channels, Some(2));
+    }
+
+    #[test]
+    fn applies_the_implied_audio_formats() {
+        // The rate and channels bits of these formats are ignored
+        let packet = audio(&[0xbf, 0]).unwrap();
+        assert_eq!(packet.codec, AudioCodec::Speex);
+        assert_eq!((packet.rate, packet.channels), (Some(16_000), Some(1)));
+
+        let packet = audio(&[0x5f, 0]).unwrap();
+        assert_eq!(packet.codec, AudioCodec::Nellymoser);
+        assert_eq!((packet.rate, packet.channels), (Some(8_000), Some(1)));
+
+        let packet = audio(&[0xef, 0]).unwrap();
+        assert_eq!(packet.codec, AudioCodec::Mp3);
+        assert_eq!((packet.rate, packet.channels), (Some(8_000), Some(1)));
+
+        let packet = audio(&[0x7f, 0]).unwrap();
+        assert_eq!(packet.codec, AudioCodec::ALaw);
+        assert_eq!((packet.rate, packet.channels), (Some(8_000), Some(1)));
+
+        let packet = audio(&[0x8f, 0]).unwrap();
+        assert_eq!(packet.codec, AudioCodec::MuLaw);
+    }
+
+    #[test]
+    fn rejects_unsupported_legacy_audio_tags() {
+        // Device specific sound, empty tag
+        assert!(audio(&[0xf2, 0]).is_none());
+        assert!(audio(&[0x2f]).is_none());
+    }
+
+    #[test]
+    fn maps_fourccs_back_and_forth() {
+        for codec in &ENHANCED_VIDEO_CODECS {
+            let fourcc = codec.fourcc().unwrap();
+            assert_eq!(VideoCodec::from_fourcc(fourcc.as_bytes()), Some(*codec));
+        }
+        assert_eq!(VideoCodec::Vp6.fourcc(), None);
+    }
+}
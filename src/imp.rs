@@ -1,20 +1,32 @@
-use crate::connection::{Connection, ConnectionError, ReadResult};
-use crate::data::{MediaType, RtmpInput};
-use crate::server::{Server, ServerResult};
+use crate::codec::{AacConfig, AvcConfig};
+use crate::data::{ChannelKey, DataMessage, Media, MediaType, PushStatus, RtmpInput};
+use crate::flv;
+use crate::flv::{
+    AudioCodec, AudioPacket, AudioPacketType, VideoCodec, VideoPacket, VideoPacketType,
+};
+use crate::queue::{Leaky, MediaQueue, QueueError, QueueLimits};
+use crate::registry;
+use crate::registry::{SharedServer, CONTEXT_TYPE};
+use crate::relay::PushTarget;
+use crate::server::{ServerConfig, ServerListener, Subscription};
+use crate::stats::{ConnectionStats, Role, ServerStats, StatsHandle};
+use crate::tls;
+use bytes::Bytes;
 use glib::subclass;
 use glib::subclass::prelude::*;
+use glib::GEnum;
 use gst::prelude::*;
 use gst::subclass::prelude::*;
-use gst::{gst_debug, gst_trace, gst_info};
+use gst::{gst_debug, gst_info, gst_trace, gst_warning};
 use gst_base::prelude::*;
 use gst_base::subclass::prelude::*;
 use once_cell::sync::Lazy;
-use slab::Slab;
-use std::collections::HashSet;
-use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
-use std::sync::Mutex;
-use std::{thread, u32};
+use rml_amf0::Amf0Value;
+use rml_rtmp::sessions::StreamMetadata;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use std::{mem, u32};
 
 static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
     gst::DebugCategory::new(
@@ -26,12 +38,51 @@ static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
 
 const DEFAULT_ADDRESS: &str = "0.0.0.0";
 const DEFAULT_PORT: u32 = 5000;
+const DEFAULT_MODE: Mode = Mode::Demux;
+const DEFAULT_EOS_ON_DISCONNECT: bool = true;
+const DEFAULT_CONNECT_TIMEOUT: u32 = 0;
+const DEFAULT_STALL_TIMEOUT: u32 = 0;
+const DEFAULT_HANDSHAKE_TIMEOUT: u32 = 0;
+const DEFAULT_DROP_STALLED: bool = false;
+const DEFAULT_EOS_ON_TIMEOUT: bool = false;
+const DEFAULT_MAX_BUFFERS: u32 = 0;
+const DEFAULT_MAX_BYTES: u32 = 0;
+const DEFAULT_MAX_TIME: u32 = 0;
+const DEFAULT_LEAKY: Leaky = Leaky::Downstream;
+const DEFAULT_MAX_LATENCY: u32 = 0;
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "GstRtmpSvrSrcMode")]
+pub enum Mode {
+    #[genum(
+        name = "Demux: Elementary streams on the video and audio sometimes pads",
+        nick = "demux"
+    )]
+    Demux = 0,
+    #[genum(name = "FLV: FLV byte stream on the src pad", nick = "flv")]
+    Flv = 1,
+}
 
 #[derive(Debug, Clone)]
 struct Settings {
     address: String,
     port: u32,
-    stream_key: Option<String>,
+    app_names: Vec<String>,
+    stream_keys: Vec<String>,
+    push_targets: Vec<PushTarget>,
+    secret: Option<String>,
+    tls_certificate: Option<String>,
+    tls_key: Option<String>,
+    mode: Mode,
+    eos_on_disconnect: bool,
+    /// In milliseconds, 0 when disabled
+    connect_timeout: u32,
+    stall_timeout: u32,
+    handshake_timeout: u32,
+    drop_stalled: bool,
+    eos_on_timeout: bool,
+    queue_limits: QueueLimits,
 }
 
 impl Default for Settings {
@@ -39,12 +90,31 @@ impl Default for Settings {
         Settings {
             address: DEFAULT_ADDRESS.into(),
             port: DEFAULT_PORT,
-            stream_key: None,
+            app_names: Vec::new(),
+            stream_keys: Vec::new(),
+            push_targets: Vec::new(),
+            secret: None,
+            tls_certificate: None,
+            tls_key: None,
+            mode: DEFAULT_MODE,
+            eos_on_disconnect: DEFAULT_EOS_ON_DISCONNECT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            drop_stalled: DEFAULT_DROP_STALLED,
+            eos_on_timeout: DEFAULT_EOS_ON_TIMEOUT,
+            queue_limits: QueueLimits {
+                max_buffers: DEFAULT_MAX_BUFFERS,
+                max_bytes: DEFAULT_MAX_BYTES,
+                max_time: DEFAULT_MAX_TIME,
+                leaky: DEFAULT_LEAKY,
+                max_latency: DEFAULT_MAX_LATENCY,
+            },
         }
     }
 }
 
-static PROPERTIES: [subclass::Property; 3] = [
+static PROPERTIES: [subclass::Property; 23] = [
     subclass::Property("address", |name| {
         glib::ParamSpec::string(
             name,
@@ -65,26 +135,645 @@ static PROPERTIES: [subclass::Property; 3] = [
             glib::ParamFlags::READWRITE,
         )
     }),
+    subclass::Property("app", |name| {
+        glib::ParamSpec::string(
+            name,
+            "Application",
+            "The RTMP application name clients can connect to, any name is accepted when not set",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("apps", |name| {
+        glib::ParamSpec::array(
+            name,
+            "Applications",
+            "The RTMP application names clients can connect to, any name is accepted when empty",
+            &glib::ParamSpec::string(
+                "app",
+                "Application",
+                "Accepted application name",
+                None,
+                glib::ParamFlags::READWRITE,
+            ),
+            glib::ParamFlags::READWRITE,
+        )
+    }),
     subclass::Property("stream_key", |name| {
         glib::ParamSpec::string(
             name,
             "Stream Key",
-            "The stream key to expect content to be published",
-            DEFAULT_ADDRESS.into(),
+            "The stream key to expect content to be published, any key is accepted when not set",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("stream_keys", |name| {
+        glib::ParamSpec::array(
+            name,
+            "Stream Keys",
+            "The stream keys to accept content to be published to, any key is accepted when empty",
+            &glib::ParamSpec::string(
+                "stream_key",
+                "Stream Key",
+                "Accepted stream key",
+                None,
+                glib::ParamFlags::READWRITE,
+            ),
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("push_targets", |name| {
+        glib::ParamSpec::array(
+            name,
+            "Push Targets",
+            "RTMP servers the published content is forwarded to, as \"<stream key>=<rtmp url>\" \
+             entries where the stream key can be \"*\" to forward every stream",
+            &glib::ParamSpec::string(
+                "push_target",
+                "Push Target",
+                "Stream key and url of the server to forward the content to",
+                None,
+                glib::ParamFlags::READWRITE,
+            ),
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("secret", |name| {
+        glib::ParamSpec::string(
+            name,
+            "Secret",
+            "Secret publishers sign their stream keys with, as a \"token\" (the hex HMAC-SHA256 of \
+             \"<app>/<stream key>/<expires>\") and an \"expires\" unix timestamp in the query \
+             string of the stream key, no signature is required when not set",
+            None,
+            glib::ParamFlags::WRITABLE,
+        )
+    }),
+    subclass::Property("tls_certificate", |name| {
+        glib::ParamSpec::string(
+            name,
+            "TLS Certificate",
+            "PEM file with the certificate chain of the server, clients connect with RTMPS when set",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("tls_key", |name| {
+        glib::ParamSpec::string(
+            name,
+            "TLS Key",
+            "PEM file with the private key of the TLS certificate",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("mode", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Mode",
+            "How the received content is output",
+            Mode::static_type(),
+            DEFAULT_MODE as i32,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("eos_on_disconnect", |name| {
+        glib::ParamSpec::boolean(
+            name,
+            "EOS on disconnect",
+            "Send EOS and remove the pads of a publisher when it disconnects, instead of keeping \
+             them for when it publishes again",
+            DEFAULT_EOS_ON_DISCONNECT,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("connect_timeout", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Connect Timeout",
+            "Milliseconds to wait for a first publisher after starting before posting a warning, \
+             0 to wait forever",
+            0,
+            u32::MAX,
+            DEFAULT_CONNECT_TIMEOUT,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("stall_timeout", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Stall Timeout",
+            "Milliseconds a publisher can go without sending media before posting a warning, 0 to \
+             wait forever",
+            0,
+            u32::MAX,
+            DEFAULT_STALL_TIMEOUT,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("handshake_timeout", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Handshake Timeout",
+            "Milliseconds a client has to complete the RTMP handshake before being disconnected \
+             with a warning, 0 to wait forever",
+            0,
+            u32::MAX,
+            DEFAULT_HANDSHAKE_TIMEOUT,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("drop_stalled", |name| {
+        glib::ParamSpec::boolean(
+            name,
+            "Drop stalled",
+            "Disconnect the publishers reaching the stall timeout",
+            DEFAULT_DROP_STALLED,
             glib::ParamFlags::READWRITE,
         )
     }),
+    subclass::Property("eos_on_timeout", |name| {
+        glib::ParamSpec::boolean(
+            name,
+            "EOS on timeout",
+            "Send EOS when reaching the connect or the stall timeout",
+            DEFAULT_EOS_ON_TIMEOUT,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("max_buffers", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Max Buffers",
+            "Media buffers waiting to be pushed before some get dropped, 0 for no limit",
+            0,
+            u32::MAX,
+            DEFAULT_MAX_BUFFERS,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("max_bytes", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Max Bytes",
+            "Bytes of media waiting to be pushed before some get dropped, 0 for no limit",
+            0,
+            u32::MAX,
+            DEFAULT_MAX_BYTES,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("max_time", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Max Time",
+            "Milliseconds of media of a publisher waiting to be pushed before some get dropped, 0 \
+             for no limit",
+            0,
+            u32::MAX,
+            DEFAULT_MAX_TIME,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("leaky", |name| {
+        glib::ParamSpec::enum_(
+            name,
+            "Leaky",
+            "Which media is dropped when a limit is reached, the video frames depending on a \
+             dropped one are dropped as well until the next keyframe",
+            Leaky::static_type(),
+            DEFAULT_LEAKY as i32,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("max_latency", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Max Latency",
+            "Milliseconds the media about to be pushed can lag behind the newest received media \
+             of its publisher before skipping to the latest keyframe, 0 to never skip",
+            0,
+            u32::MAX,
+            DEFAULT_MAX_LATENCY,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("stats", |name| {
+        glib::ParamSpec::boxed(
+            name,
+            "Statistics",
+            "State of the server and counters of each of its connections",
+            gst::Structure::static_type(),
+            glib::ParamFlags::READABLE,
+        )
+    }),
 ];
 
-#[derive(Debug)]
+/// Emits the signals of the element for the events of the server
+struct SignalListener {
+    element: glib::WeakRef<super::RtmpSrvSrc>,
+}
+
+impl ServerListener for SignalListener {
+    fn client_connected(&self, connection_id: usize, address: &str) {
+        if let Some(element) = self.element.upgrade() {
+            let _ = element.emit("client-connected", &[&(connection_id as u64), &address]);
+        }
+    }
+
+    fn connect_requested(
+        &self,
+        connection_id: usize,
+        app_name: &str,
+        tc_url: Option<&str>,
+        flash_version: Option<&str>,
+    ) {
+        if let Some(element) = self.element.upgrade() {
+            let _ = element.emit(
+                "connect-requested",
+                &[&(connection_id as u64), &app_name, &tc_url, &flash_version],
+            );
+        }
+    }
+
+    fn publish_requested(
+        &self,
+        connection_id: usize,
+        app_name: &str,
+        stream_key: &str,
+        mode: &str,
+    ) -> bool {
+        let element = match self.element.upgrade() {
+            Some(element) => element,
+            None => return false,
+        };
+
+        // Accepted unless a handler says otherwise
+        element
+            .emit(
+                "publish-requested",
+                &[&(connection_id as u64), &app_name, &stream_key, &mode],
+            )
+            .ok()
+            .flatten()
+            .and_then(|value| value.get_some::<bool>().ok())
+            .unwrap_or(true)
+    }
+
+    fn publish_ended(&self, app_name: &str, stream_key: &str) {
+        if let Some(element) = self.element.upgrade() {
+            let _ = element.emit("publish-ended", &[&app_name, &stream_key]);
+        }
+    }
+
+    fn client_disconnected(&self, connection_id: usize) {
+        if let Some(element) = self.element.upgrade() {
+            let _ = element.emit("client-disconnected", &[&(connection_id as u64)]);
+        }
+    }
+
+    fn handshake_timed_out(&self, connection_id: usize) {
+        if let Some(element) = self.element.upgrade() {
+            gst::element_warning!(
+                element,
+                gst::ResourceError::Read,
+                [
+                    "Connection {} did not complete the handshake in time",
+                    connection_id
+                ]
+            );
+        }
+    }
+}
+
+/// A sometimes pad carrying one elementary stream
+struct Stream {
+    pad: gst::Pad,
+    stream: gst::Stream,
+    caps: Option<gst::Caps>,
+    tags: Option<gst::TagList>,
+    last_dts: Option<u64>,
+    /// The next buffer follows a gap, after the publisher published again
+    discont: bool,
+}
+
+impl Stream {
+    fn new(
+        element: &super::RtmpSrvSrc,
+        template: &str,
+        name: &str,
+        stream_type: gst::StreamType,
+    ) -> Stream {
+        let templ = element
+            .get_element_class()
+            .get_pad_template(template)
+            .unwrap();
+        let pad = gst::Pad::from_template(&templ, Some(name));
+        pad.use_fixed_caps();
+        pad.set_active(true).unwrap();
+
+        let stream_id = format!("{}/{}", element.get_name(), name);
+        let stream = gst::Stream::new(
+            Some(&stream_id),
+            None,
+            stream_type,
+            gst::StreamFlags::empty(),
+        );
+        pad.push_event(
+            gst::event::StreamStart::builder(&stream_id)
+                .stream(stream.clone())
+                .build(),
+        );
+        element.add_pad(&pad).unwrap();
+
+        Stream {
+            pad,
+            stream,
+            caps: None,
+            tags: None,
+            last_dts: None,
+            discont: false,
+        }
+    }
+
+    /// Sets the timestamps (in nanoseconds of running time) on the buffer. The duration is
+    /// estimated from the interval since the previous buffer of the track, and left unset on the
+    /// first buffer of a publishing session.
+    fn set_timestamps(&mut self, buffer: &mut gst::BufferRef, dts: u64, pts: u64) {
+        buffer.set_dts(gst::ClockTime::from_nseconds(dts));
+        buffer.set_pts(gst::ClockTime::from_nseconds(pts));
+        let duration = self
+            .last_dts
+            .and_then(|last_dts| dts.checked_sub(last_dts))
+            .filter(|duration| *duration > 0);
+        if let Some(duration) = duration {
+            buffer.set_duration(gst::ClockTime::from_nseconds(duration));
+        }
+        self.last_dts = Some(dts);
+
+        if self.discont {
+            buffer.set_flags(gst::BufferFlags::DISCONT);
+            self.discont = false;
+        }
+    }
+
+    /// Starts the stream over for a new publishing session on the same pad, the caps, segment
+    /// and tags are sent again with the next caps
+    fn restart(&mut self) {
+        if let Some(stream_id) = self.stream.get_stream_id() {
+            self.pad.push_event(
+                gst::event::StreamStart::builder(&stream_id)
+                    .stream(self.stream.clone())
+                    .build(),
+            );
+        }
+        self.caps = None;
+        self.last_dts = None;
+        self.discont = true;
+    }
+
+    fn set_caps(&mut self, caps: gst::Caps) {
+        if self.caps.as_ref() == Some(&caps) {
+            return;
+        }
+
+        self.stream.set_caps(Some(&caps));
+        self.pad.push_event(gst::event::Caps::new(&caps));
+        if self.caps.is_none() {
+            // The segment has to follow the first caps event, and the tags the segment
+            let segment = gst::FormattedSegment::<gst::ClockTime>::new();
+            self.pad.push_event(gst::event::Segment::new(&segment));
+            if let Some(ref tags) = self.tags {
+                self.pad.push_event(gst::event::Tag::new(tags.clone()));
+            }
+        }
+        self.caps = Some(caps);
+    }
+
+    fn set_tags(&mut self, tags: gst::TagList) {
+        if self.tags.as_ref() == Some(&tags) {
+            return;
+        }
+
+        self.stream.set_tags(Some(&tags));
+        if self.caps.is_some() {
+            self.pad.push_event(gst::event::Tag::new(tags.clone()));
+        }
+        self.tags = Some(tags);
+    }
+}
+
+/// Maps the RTMP timestamps (in milliseconds) of a publishing session to running time (in
+/// nanoseconds), relative to the first received tag
+#[derive(Default)]
+struct Timeline {
+    /// Running time the first received tag was received at
+    base_running_time: Option<u64>,
+    /// Latest RTMP timestamp and its distance in milliseconds to the first one. The distance adds
+    /// up the differences between successive timestamps, so that it carries on growing when the
+    /// 32 bits timestamps wrap around.
+    last: Option<(u32, i64)>,
+    /// Running time the timestamps of a new publishing session can not go below
+    min_running_time: u64,
+}
+
+impl Timeline {
+    /// Starts over for a new publishing session, from the given running time on
+    fn restart(&mut self, min_running_time: u64) {
+        self.base_running_time = None;
+        self.last = None;
+        self.min_running_time = self.min_running_time.max(min_running_time);
+    }
+
+    fn running_time(&mut self, element: &super::RtmpSrvSrc, timestamp: u32) -> u64 {
+        let min_running_time = self.min_running_time;
+        let base_running_time = *self.base_running_time.get_or_insert_with(|| {
+            let running_time = element
+                .get_clock()
+                .and_then(|clock| {
+                    let now = clock.get_time().nseconds()?;
+                    let base_time = element.get_base_time().nseconds()?;
+                    now.checked_sub(base_time)
+                })
+                .unwrap_or(0)
+                .max(min_running_time);
+
+            gst_debug!(
+                CAT,
+                obj: element,
+                "First tag with timestamp {}ms received at running time {}ns",
+                timestamp,
+                running_time
+            );
+            running_time
+        });
+
+        // The tags of the tracks are not necessarily in order, so the difference is signed
+        let distance = match self.last {
+            Some((last_timestamp, last_distance)) => {
+                last_distance + timestamp.wrapping_sub(last_timestamp) as i32 as i64
+            }
+            None => 0,
+        };
+        self.last = Some((timestamp, distance));
+        (base_running_time as i64 + distance * 1_000_000).max(0) as u64
+    }
+}
+
+/// The pads of one publisher, named after its stream key
+struct Publisher {
+    name: String,
+    metadata: Option<StreamMetadata>,
+    timeline: Timeline,
+    video: Option<Stream>,
+    audio: Option<Stream>,
+    /// The data messages of the publisher, created with the first one
+    data: Option<Stream>,
+    /// Codec and configuration of the latest audio sequence header
+    audio_config: Option<(AudioCodec, Bytes)>,
+    /// Channel count of the Enhanced RTMP multichannel configuration
+    audio_channels: Option<u8>,
+    /// Every property of the latest onMetaData, turned into the tags of the streams
+    metadata_properties: Option<HashMap<String, Amf0Value>>,
+    /// Cleared when the publisher disconnects while its pads are kept
+    is_publishing: bool,
+}
+
+impl Publisher {
+    fn new(name: String) -> Publisher {
+        Publisher {
+            name,
+            metadata: None,
+            timeline: Timeline::default(),
+            video: None,
+            audio: None,
+            data: None,
+            audio_config: None,
+            audio_channels: None,
+            metadata_properties: None,
+            is_publishing: true,
+        }
+    }
+
+    /// Keeps the pads for the next publishing session, whose timestamps start over
+    fn restart(&mut self) {
+        let min_running_time = self
+            .streams()
+            .filter_map(|stream| stream.last_dts)
+            .max()
+            .map_or(0, |dts| dts + 1);
+        self.timeline.restart(min_running_time);
+        self.audio_config = None;
+        self.audio_channels = None;
+        for stream in self
+            .video
+            .iter_mut()
+            .chain(self.audio.iter_mut())
+            .chain(self.data.iter_mut())
+        {
+            stream.restart();
+        }
+        self.is_publishing = true;
+    }
+
+    fn audio_caps(&self, codec: AudioCodec, rate: Option<u32>, channels: Option<u8>) -> gst::Caps {
+        let config = match self.audio_config {
+            Some((config_codec, ref config)) if config_codec == codec => config.clone(),
+            _ => Bytes::new(),
+        };
+        audio_caps(
+            codec,
+            &config,
+            rate,
+            channels.or(self.audio_channels),
+            self.metadata.as_ref(),
+        )
+    }
+
+    fn streams(&self) -> impl Iterator<Item = &Stream> {
+        self.video
+            .iter()
+            .chain(self.audio.iter())
+            .chain(self.data.iter())
+    }
+
+    fn running_time(&mut self, element: &super::RtmpSrvSrc, timestamp: u32) -> u64 {
+        self.timeline.running_time(element, timestamp)
+    }
+}
+
+/// Content of the flv publisher received before its first frame, held back as the file header
+/// has to announce the tracks first
+#[derive(Default)]
+struct FlvPrelude {
+    has_audio: bool,
+    has_video: bool,
+    /// Metadata and sequence header tags
+    tags: Vec<u8>,
+}
+
+struct Started {
+    mode: Mode,
+    eos_on_disconnect: bool,
+    connect_timeout: Option<Duration>,
+    stall_timeout: Option<Duration>,
+    drop_stalled: bool,
+    eos_on_timeout: bool,
+    started_at: Instant,
+    /// Cleared once a first publisher started, or the connect timeout was reported
+    waiting_publisher: bool,
+    /// When the media of each publisher was last received, removed once the stall is reported
+    last_media: HashMap<ChannelKey, Instant>,
+    position: u64,
+    publishers: HashMap<ChannelKey, Publisher>,
+    /// Set once the src pad, which carries nothing in demux mode, got its EOS
+    src_pad_done: bool,
+    /// The publisher whose content goes out on the src pad in flv mode
+    flv_publisher: Option<ChannelKey>,
+    /// The next flv buffer follows skipped content
+    flv_discont: bool,
+    /// Set until the file header is written
+    flv_prelude: Option<FlvPrelude>,
+    /// Frame following the file header, output by the next call
+    flv_next: Option<gst::Buffer>,
+    flv_timeline: Timeline,
+    /// Added to the timestamps of the flv publisher so that the tags of a new publisher follow the
+    /// previous ones, computed with its first tag when not set
+    flv_timestamp_offset: Option<u32>,
+    flv_last_timestamp: Option<u32>,
+    flv_last_running_time: Option<u64>,
+    flow_combiner: gst_base::UniqueFlowCombiner,
+    server: Arc<SharedServer>,
+    subscription_id: u64,
+}
+
+impl Started {
+    /// When the next connect or stall timeout is reached, if any
+    fn timeout_deadline(&self) -> Option<Instant> {
+        let connect_deadline = match self.connect_timeout {
+            Some(timeout) if self.waiting_publisher => Some(self.started_at + timeout),
+            _ => None,
+        };
+        let stall_deadline = self.stall_timeout.and_then(|timeout| {
+            self.last_media
+                .values()
+                .map(|last_media| *last_media + timeout)
+                .min()
+        });
+
+        match (connect_deadline, stall_deadline) {
+            (Some(connect_deadline), Some(stall_deadline)) => {
+                Some(connect_deadline.min(stall_deadline))
+            }
+            (connect_deadline, stall_deadline) => connect_deadline.or(stall_deadline),
+        }
+    }
+}
+
 enum State {
     Stopped,
-    Started {
-        source: Receiver<RtmpInput>,
-        position: u64,
-        video_caps: Option<gst::Caps>,
-        audio_caps: Option<gst::Caps>,
-    },
+    Started(Started),
 }
 
 impl Default for State {
@@ -96,6 +785,12 @@ impl Default for State {
 pub struct RtmpSvrSrc {
     settings: Mutex<Settings>,
     state: Mutex<State>,
+    queue: Arc<MediaQueue>,
+    /// Context set by the pipeline, pointing to a server started by another element
+    context: Mutex<Option<gst::Context>>,
+    /// Counters of the server while started, apart from the state which the streaming thread
+    /// keeps locked while pushing
+    stats_handle: Mutex<Option<StatsHandle>>,
 }
 
 impl ObjectSubclass for RtmpSvrSrc {
@@ -115,7 +810,9 @@ impl ObjectSubclass for RtmpSvrSrc {
             "Rafael Caricio <rafael@caricio.com>",
         );
 
-        let caps = gst::Caps::new_any();
+        // In demux mode the always pad of the base class only drives the streaming thread and is
+        // done right away, media is pushed on the sometimes pads created as the content arrives
+        let caps = gst::Caps::new_simple("video/x-flv", &[]);
         let src_pad_template = gst::PadTemplate::new(
             "src",
             gst::PadDirection::Src,
@@ -125,13 +822,129 @@ impl ObjectSubclass for RtmpSvrSrc {
         .unwrap();
         klass.add_pad_template(src_pad_template);
 
+        let mut caps = gst::Caps::new_empty();
+        {
+            let caps = caps.get_mut().unwrap();
+            for codec in &[
+                VideoCodec::Avc,
+                VideoCodec::Hevc,
+                VideoCodec::Av1,
+                VideoCodec::Vp9,
+                VideoCodec::SorensonH263,
+                VideoCodec::ScreenVideo,
+                VideoCodec::ScreenVideo2,
+                VideoCodec::Vp6,
+                VideoCodec::Vp6Alpha,
+            ] {
+                caps.append_structure(video_codec_structure(*codec));
+            }
+        }
+        let video_pad_template = gst::PadTemplate::new(
+            "video_%s",
+            gst::PadDirection::Src,
+            gst::PadPresence::Sometimes,
+            &caps,
+        )
+        .unwrap();
+        klass.add_pad_template(video_pad_template);
+
+        let mut caps = gst::Caps::new_empty();
+        {
+            let caps = caps.get_mut().unwrap();
+            for codec in &[
+                AudioCodec::Aac,
+                AudioCodec::Opus,
+                AudioCodec::Flac,
+                AudioCodec::Ac3,
+                AudioCodec::Eac3,
+                AudioCodec::Mp3,
+                AudioCodec::Pcm(8),
+                AudioCodec::Pcm(16),
+                AudioCodec::Adpcm,
+                AudioCodec::Nellymoser,
+                AudioCodec::ALaw,
+                AudioCodec::MuLaw,
+                AudioCodec::Speex,
+            ] {
+                caps.append_structure(audio_codec_structure(*codec));
+            }
+        }
+        let audio_pad_template = gst::PadTemplate::new(
+            "audio_%s",
+            gst::PadDirection::Src,
+            gst::PadPresence::Sometimes,
+            &caps,
+        )
+        .unwrap();
+        klass.add_pad_template(audio_pad_template);
+
+        let caps = gst::Caps::new_simple("application/x-amf0", &[]);
+        let data_pad_template = gst::PadTemplate::new(
+            "data_%s",
+            gst::PadDirection::Src,
+            gst::PadPresence::Sometimes,
+            &caps,
+        )
+        .unwrap();
+        klass.add_pad_template(data_pad_template);
+
         klass.install_properties(&PROPERTIES);
+
+        // Emitted from the server thread, which waits for the handlers to return
+        klass.add_signal(
+            "client-connected",
+            glib::SignalFlags::RUN_LAST,
+            &[u64::static_type(), String::static_type()],
+            glib::types::Type::Unit,
+        );
+        klass.add_signal(
+            "connect-requested",
+            glib::SignalFlags::RUN_LAST,
+            &[
+                u64::static_type(),
+                String::static_type(),
+                String::static_type(),
+                String::static_type(),
+            ],
+            glib::types::Type::Unit,
+        );
+        klass.add_signal_with_accumulator(
+            "publish-requested",
+            glib::SignalFlags::RUN_LAST,
+            &[
+                u64::static_type(),
+                String::static_type(),
+                String::static_type(),
+                String::static_type(),
+            ],
+            bool::static_type(),
+            |_, accumulated, value| {
+                // The first handler rejecting the publisher stops the emission
+                *accumulated = value.clone();
+                value.get_some::<bool>().unwrap_or(true)
+            },
+        );
+        klass.add_signal(
+            "publish-ended",
+            glib::SignalFlags::RUN_LAST,
+            &[String::static_type(), String::static_type()],
+            glib::types::Type::Unit,
+        );
+        klass.add_signal(
+            "client-disconnected",
+            glib::SignalFlags::RUN_LAST,
+            &[u64::static_type()],
+            glib::types::Type::Unit,
+        );
     }
 
     fn new() -> Self {
         Self {
             settings: Mutex::new(Default::default()),
             state: Mutex::new(Default::default()),
+            queue: Arc::new(MediaQueue::new()),
+            context: Mutex::new(None),
+            stats_handle: Mutex::new(None),
         }
     }
 }
@@ -156,84 +969,319 @@ impl ObjectImpl for RtmpSvrSrc {
                 settings.port = port;
                 gst_debug!(CAT, obj: obj, "Set port to: {}", port);
             }
-            _ => unimplemented!(),
-        };
-    }
-
-    fn get_property(&self, obj: &Self::Type, id: usize) -> glib::Value {
-        let prop = &PROPERTIES[id];
-        match *prop {
-            subclass::Property("address", ..) => {
-                let settings = self.settings.lock().unwrap();
-                settings.address.to_value()
+            subclass::Property("app", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let app_name: Option<String> = value.get().expect("type checked upstream");
+                settings.app_names = app_name.into_iter().collect();
+                gst_debug!(CAT, obj: obj, "Set applications to: {:?}", settings.app_names);
             }
-            subclass::Property("port", ..) => {
-                let settings = self.settings.lock().unwrap();
-                settings.port.to_value()
+            subclass::Property("apps", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let app_names: Option<gst::Array> = value.get().expect("type checked upstream");
+                settings.app_names = string_list(app_names.as_ref());
+                gst_debug!(CAT, obj: obj, "Set applications to: {:?}", settings.app_names);
             }
-            _ => unimplemented!(),
-        }
-    }
-
-    fn constructed(&self, obj: &Self::Type) {
-        self.parent_constructed(obj);
-        obj.set_live(true); // this is always a live source!
-        obj.set_automatic_eos(false);
-        obj.set_format(gst::Format::Bytes);
-    }
-}
-
-impl ElementImpl for RtmpSvrSrc {}
-
-impl BaseSrcImpl for RtmpSvrSrc {
-    fn start(&self, src: &Self::Type) -> Result<(), gst::ErrorMessage> {
-        // TODO: Here we start the server..
-        // TODO: consider sharing context with other gst elements
-        // - Create a socket
-
-        let (media_sender, media_receiver) = channel();
-        let mut state = self.state.lock().unwrap();
-        if let State::Started { .. } = *state {
-            return Ok(());
-        }
-
-        let settings = self.settings.lock().unwrap();
-        let address = format!("{}:{}", settings.address, settings.port);
-        let listener = TcpListener::bind(&address).map_err(|err| {
-            gst::error_msg!(
-                gst::ResourceError::Busy,
-                ["Failed to bind to address {}: {}", address, err]
-            )
-        })?;
+            subclass::Property("stream_key", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let stream_key: Option<String> = value.get().expect("type checked upstream");
+                settings.stream_keys = stream_key.into_iter().collect();
+                gst_debug!(CAT, obj: obj, "Set stream keys to: {:?}", settings.stream_keys);
+            }
+            subclass::Property("stream_keys", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let stream_keys: Option<gst::Array> = value.get().expect("type checked upstream");
+                settings.stream_keys = string_list(stream_keys.as_ref());
+                gst_debug!(CAT, obj: obj, "Set stream keys to: {:?}", settings.stream_keys);
+            }
+            subclass::Property("push_targets", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let entries: Option<gst::Array> = value.get().expect("type checked upstream");
+                settings.push_targets = string_list(entries.as_ref())
+                    .iter()
+                    .filter_map(|entry| match PushTarget::parse(entry) {
+                        Ok(target) => Some(target),
+                        Err(error) => {
+                            gst_warning!(CAT, obj: obj, "Ignoring push target: {}", error);
+                            None
+                        }
+                    })
+                    .collect();
+                gst_debug!(CAT, obj: obj, "Set push targets to: {:?}", settings.push_targets);
+            }
+            subclass::Property("secret", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.secret = value.get().expect("type checked upstream");
+                gst_debug!(
+                    CAT,
+                    obj: obj,
+                    "Stream keys signature {}",
+                    if settings.secret.is_some() {
+                        "required"
+                    } else {
+                        "not required"
+                    }
+                );
+            }
+            subclass::Property("tls_certificate", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tls_certificate = value.get().expect("type checked upstream");
+                gst_debug!(
+                    CAT,
+                    obj: obj,
+                    "Set TLS certificate to: {:?}",
+                    settings.tls_certificate
+                );
+            }
+            subclass::Property("tls_key", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tls_key = value.get().expect("type checked upstream");
+                gst_debug!(CAT, obj: obj, "Set TLS key to: {:?}", settings.tls_key);
+            }
+            subclass::Property("mode", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let mode = value.get_some().expect("type checked upstream");
+                settings.mode = mode;
+                gst_debug!(CAT, obj: obj, "Set mode to: {:?}", mode);
+            }
+            subclass::Property("eos_on_disconnect", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let eos_on_disconnect = value.get_some().expect("type checked upstream");
+                settings.eos_on_disconnect = eos_on_disconnect;
+                gst_debug!(CAT, obj: obj, "Set EOS on disconnect to: {}", eos_on_disconnect);
+            }
+            subclass::Property("connect_timeout", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let connect_timeout = value.get_some().expect("type checked upstream");
+                settings.connect_timeout = connect_timeout;
+                gst_debug!(CAT, obj: obj, "Set connect timeout to: {}ms", connect_timeout);
+            }
+            subclass::Property("stall_timeout", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let stall_timeout = value.get_some().expect("type checked upstream");
+                settings.stall_timeout = stall_timeout;
+                gst_debug!(CAT, obj: obj, "Set stall timeout to: {}ms", stall_timeout);
+            }
+            subclass::Property("handshake_timeout", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let handshake_timeout = value.get_some().expect("type checked upstream");
+                settings.handshake_timeout = handshake_timeout;
+                gst_debug!(CAT, obj: obj, "Set handshake timeout to: {}ms", handshake_timeout);
+            }
+            subclass::Property("drop_stalled", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let drop_stalled = value.get_some().expect("type checked upstream");
+                settings.drop_stalled = drop_stalled;
+                gst_debug!(CAT, obj: obj, "Set drop stalled to: {}", drop_stalled);
+            }
+            subclass::Property("eos_on_timeout", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let eos_on_timeout = value.get_some().expect("type checked upstream");
+                settings.eos_on_timeout = eos_on_timeout;
+                gst_debug!(CAT, obj: obj, "Set EOS on timeout to: {}", eos_on_timeout);
+            }
+            subclass::Property("max_buffers", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let max_buffers = value.get_some().expect("type checked upstream");
+                settings.queue_limits.max_buffers = max_buffers;
+                gst_debug!(CAT, obj: obj, "Set max buffers to: {}", max_buffers);
+            }
+            subclass::Property("max_bytes", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let max_bytes = value.get_some().expect("type checked upstream");
+                settings.queue_limits.max_bytes = max_bytes;
+                gst_debug!(CAT, obj: obj, "Set max bytes to: {}", max_bytes);
+            }
+            subclass::Property("max_time", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let max_time = value.get_some().expect("type checked upstream");
+                settings.queue_limits.max_time = max_time;
+                gst_debug!(CAT, obj: obj, "Set max time to: {}ms", max_time);
+            }
+            subclass::Property("leaky", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let leaky = value.get_some().expect("type checked upstream");
+                settings.queue_limits.leaky = leaky;
+                gst_debug!(CAT, obj: obj, "Set leaky to: {:?}", leaky);
+            }
+            subclass::Property("max_latency", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let max_latency = value.get_some().expect("type checked upstream");
+                settings.queue_limits.max_latency = max_latency;
+                gst_debug!(CAT, obj: obj, "Set max latency to: {}ms", max_latency);
+            }
+            _ => unimplemented!(),
+        };
+    }
+
+    fn get_property(&self, obj: &Self::Type, id: usize) -> glib::Value {
+        let prop = &PROPERTIES[id];
+        match *prop {
+            subclass::Property("address", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.address.to_value()
+            }
+            subclass::Property("port", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.port.to_value()
+            }
+            subclass::Property("app", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.app_names.first().map(String::as_str).to_value()
+            }
+            subclass::Property("apps", ..) => {
+                let settings = self.settings.lock().unwrap();
+                string_array(&settings.app_names).to_value()
+            }
+            subclass::Property("stream_key", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.stream_keys.first().map(String::as_str).to_value()
+            }
+            subclass::Property("stream_keys", ..) => {
+                let settings = self.settings.lock().unwrap();
+                string_array(&settings.stream_keys).to_value()
+            }
+            subclass::Property("push_targets", ..) => {
+                let settings = self.settings.lock().unwrap();
+                let entries: Vec<String> = settings
+                    .push_targets
+                    .iter()
+                    .map(PushTarget::to_string)
+                    .collect();
+                string_array(&entries).to_value()
+            }
+            subclass::Property("tls_certificate", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.tls_certificate.to_value()
+            }
+            subclass::Property("tls_key", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.tls_key.to_value()
+            }
+            subclass::Property("mode", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.mode.to_value()
+            }
+            subclass::Property("eos_on_disconnect", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.eos_on_disconnect.to_value()
+            }
+            subclass::Property("connect_timeout", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.connect_timeout.to_value()
+            }
+            subclass::Property("stall_timeout", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.stall_timeout.to_value()
+            }
+            subclass::Property("handshake_timeout", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.handshake_timeout.to_value()
+            }
+            subclass::Property("drop_stalled", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.drop_stalled.to_value()
+            }
+            subclass::Property("eos_on_timeout", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.eos_on_timeout.to_value()
+            }
+            subclass::Property("max_buffers", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.queue_limits.max_buffers.to_value()
+            }
+            subclass::Property("max_bytes", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.queue_limits.max_bytes.to_value()
+            }
+            subclass::Property("max_time", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.queue_limits.max_time.to_value()
+            }
+            subclass::Property("leaky", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.queue_limits.leaky.to_value()
+            }
+            subclass::Property("max_latency", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.queue_limits.max_latency.to_value()
+            }
+            subclass::Property("stats", ..) => self.stats().to_value(),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn constructed(&self, obj: &Self::Type) {
+        self.parent_constructed(obj);
+        obj.set_live(true); // this is always a live source!
+        obj.set_automatic_eos(false);
+        obj.set_format(gst::Format::Bytes);
+    }
+}
+
+impl ElementImpl for RtmpSvrSrc {
+    fn set_context(&self, element: &Self::Type, context: &gst::Context) {
+        if context.get_context_type() == CONTEXT_TYPE {
+            gst_debug!(CAT, obj: element, "Received server context");
+            *self.context.lock().unwrap() = Some(context.clone());
+        }
+        self.parent_set_context(element, context)
+    }
+}
 
-        let (connection_sender, connection_receiver) = channel();
+impl BaseSrcImpl for RtmpSvrSrc {
+    fn start(&self, src: &Self::Type) -> Result<(), gst::ErrorMessage> {
+        let mut state = self.state.lock().unwrap();
+        if let State::Started(_) = *state {
+            return Ok(());
+        }
 
-        // TODO: Capture the join handle and use it to gracefully shutdown
-        thread::spawn(|| handle_connections(media_sender, connection_receiver));
-        thread::spawn(|| accept_connections(connection_sender, listener));
-        *state = State::Started {
-            source: media_receiver,
+        let settings = self.settings.lock().unwrap();
+        let (server, subscription_id) = self.subscribe(src, &settings)?;
+        *state = State::Started(Started {
+            mode: settings.mode,
+            eos_on_disconnect: settings.eos_on_disconnect,
+            connect_timeout: timeout_duration(settings.connect_timeout),
+            stall_timeout: timeout_duration(settings.stall_timeout),
+            drop_stalled: settings.drop_stalled,
+            eos_on_timeout: settings.eos_on_timeout,
+            started_at: Instant::now(),
+            waiting_publisher: true,
+            last_media: HashMap::new(),
             position: 0,
-            video_caps: None,
-            audio_caps: None,
-        };
+            publishers: HashMap::new(),
+            src_pad_done: false,
+            flv_publisher: None,
+            flv_discont: false,
+            flv_prelude: Some(FlvPrelude::default()),
+            flv_next: None,
+            flv_timeline: Timeline::default(),
+            flv_timestamp_offset: None,
+            flv_last_timestamp: None,
+            flv_last_running_time: None,
+            flow_combiner: gst_base::UniqueFlowCombiner::new(),
+            server,
+            subscription_id,
+        });
 
-        // - Create channel to receive data (metadata and media data)
-        // - Create a thread that handle connections
-        //    - Wait for clients to connect
-        //    - Whenever a client connects and starts sending content
-        // - When a Connection is active, we can "start" the RtmpSvrSrc to publish buffers of
-        //   content to the stream.
         Ok(())
     }
 
     fn stop(&self, src: &Self::Type) -> Result<(), gst::ErrorMessage> {
         gst_debug!(CAT, obj: src, "Stopping");
-        // TODO: Here we stop the server
-        // - Notify the server to stop accepting connections
-        // - Notify the connections the stream ended
         let mut state = self.state.lock().unwrap();
-        *state = State::Stopped;
+        if let State::Started(started) = mem::replace(&mut *state, State::Stopped) {
+            for stream in started.publishers.values().flat_map(Publisher::streams) {
+                let _ = stream.pad.set_active(false);
+                let _ = src.remove_pad(&stream.pad);
+            }
+
+            // The server keeps running as long as other elements are using it
+            *self.stats_handle.lock().unwrap() = None;
+            started.server.unsubscribe(started.subscription_id);
+            SharedServer::release(started.server);
+            gst_debug!(CAT, obj: src, "Server released");
+        }
+
         Ok(())
     }
 
@@ -260,215 +1308,1362 @@ impl BaseSrcImpl for RtmpSvrSrc {
         }
     }
 
-    fn unlock(&self, _src: &Self::Type) -> Result<(), gst::ErrorMessage> {
-        // TODO: Here we abort the server
+    fn unlock(&self, src: &Self::Type) -> Result<(), gst::ErrorMessage> {
+        gst_debug!(CAT, obj: src, "Unlocking");
+        self.queue.set_flushing(true);
+        Ok(())
+    }
+
+    fn unlock_stop(&self, src: &Self::Type) -> Result<(), gst::ErrorMessage> {
+        gst_debug!(CAT, obj: src, "Unlock stop");
+        self.queue.set_flushing(false);
         Ok(())
     }
 }
 
-impl PushSrcImpl for RtmpSvrSrc {
-    fn create(&self, src: &Self::Type) -> Result<gst::Buffer, gst::FlowError> {
-        let mut state = self.state.lock().unwrap();
-        let (source, position, video_caps, audio_caps) = match *state {
-            State::Stopped => {
-                gst::element_error!(src, gst::LibraryError::Failed, ["Not started yet"]);
-                return Err(gst::FlowError::Error);
-            }
-            State::Started {
-                ref source,
-                ref mut position,
-                ref mut video_caps,
-                ref mut audio_caps,
-            } => (source, position, video_caps, audio_caps),
+impl RtmpSvrSrc {
+    /// Counters of the server shared by the element, and of each of its connections
+    fn stats(&self) -> gst::Structure {
+        let handle = self.stats_handle.lock().unwrap().clone();
+        let stats = handle
+            .as_ref()
+            .map_or_else(ServerStats::default, StatsHandle::snapshot);
+
+        let connections = stats
+            .connections
+            .iter()
+            .map(|(id, connection)| {
+                self.connection_stats(*id, connection, &stats)
+                    .to_send_value()
+            })
+            .collect();
+
+        let mut structure = gst::Structure::builder("rtmpsvrsrc-stats")
+            .field("listening", &handle.is_some())
+            .field("total-connections", &stats.total_connections)
+            .field("connections", &gst::Array::from_owned(connections));
+        if let Some(ref handle) = handle {
+            structure = structure.field("address", &handle.address());
+        }
+        structure.build()
+    }
+
+    fn connection_stats(
+        &self,
+        id: usize,
+        connection: &ConnectionStats,
+        stats: &ServerStats,
+    ) -> gst::Structure {
+        let connected_since = connection
+            .connected_since
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let mut dropped_frames = connection.dropped_frames;
+        if let (Some(Role::Publisher), Some(ref channel)) = (connection.role, &connection.channel) {
+            dropped_frames += self.queue.dropped(channel);
+        }
+
+        let mut structure = gst::Structure::builder("rtmpsvrsrc-connection-stats")
+            .field("id", &(id as u64))
+            .field("peer-address", &connection.peer_address)
+            .field("connected-since", &connected_since)
+            .field("bytes-in", &connection.bytes_in)
+            .field("bytes-out", &connection.bytes_out)
+            .field("video-tags", &connection.video_tags)
+            .field("audio-tags", &connection.audio_tags)
+            .field("bitrate", &connection.bitrate)
+            .field("dropped-frames", &dropped_frames);
+        if let Some(role) = connection.role {
+            structure = structure.field("role", &role.nick());
+        }
+        if let Some(ref channel) = connection.channel {
+            structure = structure
+                .field("app", &channel.app_name)
+                .field("stream-key", &channel.stream_key)
+                .field("watchers", &stats.watchers(channel));
+        }
+        if let Some(interval) = connection.keyframe_interval {
+            structure = structure.field("keyframe-interval", &(interval as u64 * 1_000_000));
+        }
+        if let Some(timestamp) = connection.last_timestamp {
+            structure = structure.field("last-timestamp", &(timestamp as u64 * 1_000_000));
+        }
+        structure.build()
+    }
+
+    /// Lets the application know about the state of the forwarding to a push target
+    fn post_push_status(
+        &self,
+        element: &super::RtmpSrvSrc,
+        channel: &ChannelKey,
+        url: &str,
+        status: &PushStatus,
+    ) {
+        gst_info!(CAT, obj: element, "Push of {} to {}: {:?}", channel, url, status);
+
+        let mut structure = gst::Structure::builder("rtmpsvrsrc-push-status")
+            .field("app", &channel.app_name)
+            .field("stream-key", &channel.stream_key)
+            .field("url", &url)
+            .field("status", &status.nick());
+        if let PushStatus::Disconnected(ref reason) = status {
+            structure = structure.field("reason", reason);
+        }
+
+        let _ = element.post_message(
+            gst::message::Element::builder(structure.build())
+                .src(Some(element))
+                .build(),
+        );
+    }
+
+    /// Lets the application know about the media dropped because of the limits of the queue, like
+    /// the QoS messages of the sinks
+    fn post_drops(&self, element: &super::RtmpSrvSrc) {
+        for (channel, counts) in self.queue.take_drops() {
+            gst_debug!(
+                CAT,
+                obj: element,
+                "Dropped {} buffers of {}, {} in total",
+                counts.pending,
+                channel,
+                counts.dropped
+            );
+
+            let structure = gst::Structure::builder("rtmpsvrsrc-qos")
+                .field("app", &channel.app_name)
+                .field("stream-key", &channel.stream_key)
+                .field("processed", &counts.processed)
+                .field("dropped", &counts.dropped)
+                .build();
+            let _ = element.post_message(
+                gst::message::Element::builder(structure)
+                    .src(Some(element))
+                    .build(),
+            );
+        }
+    }
+
+    fn flv_buffer(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+        data: Vec<u8>,
+    ) -> gst::Buffer {
+        let size = data.len() as u64;
+        let offset = state.position;
+        state.position += size;
+
+        gst_trace!(
+            CAT,
+            obj: element,
+            "FLV chunk of {} bytes at offset {}",
+            size,
+            offset
+        );
+
+        let mut buffer = gst::Buffer::from_mut_slice(data);
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_offset(offset);
+            buffer.set_offset_end(offset + size);
+            if state.flv_discont {
+                buffer.set_flags(gst::BufferFlags::DISCONT);
+                state.flv_discont = false;
+            }
+        }
+
+        buffer
+    }
+
+    /// Turns the media of the flv publisher into a tag, timestamped with its running time. The
+    /// sequence headers are held back until the first frame, which comes after the file header.
+    fn flv_media(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+        media: Media,
+    ) -> Result<Option<gst::Buffer>, gst::FlowError> {
+        let (tag_type, is_sequence_header, composition_time) = match media.media_type {
+            MediaType::Video => {
+                let packet = VideoPacket::parse(&media.data);
+                (
+                    flv::TAG_TYPE_VIDEO,
+                    packet.as_ref().map_or(false, |packet| {
+                        packet.packet_type == VideoPacketType::SequenceHeader
+                    }),
+                    packet.map_or(0, |packet| packet.composition_time),
+                )
+            }
+            MediaType::Audio => (
+                flv::TAG_TYPE_AUDIO,
+                AudioPacket::parse(&media.data).map_or(false, |packet| {
+                    packet.packet_type == AudioPacketType::SequenceHeader
+                        || packet.packet_type == AudioPacketType::MultichannelConfig
+                }),
+                0,
+            ),
         };
 
-        loop {
-            let input = match source.try_recv() {
-                Result::Ok(i) => i,
-                Result::Err(TryRecvError::Empty) => continue, // blocks waiting for content
-                Result::Err(TryRecvError::Disconnected) => {
-                    return Err(gst::FlowError::Eos);
-                }
-            };
+        let last_timestamp = state.flv_last_timestamp;
+        let offset = *state.flv_timestamp_offset.get_or_insert_with(|| {
+            last_timestamp.map_or(0, |last| last.wrapping_add(1).wrapping_sub(media.timestamp))
+        });
+        let timestamp = media.timestamp.wrapping_add(offset);
+        state.flv_last_timestamp = Some(timestamp);
+        let tag = flv::tag(tag_type, timestamp, &media.data);
 
-            match input {
-                RtmpInput::Metadata(metadata) => {
-                    println!("Metadata: {:?}", metadata);
+        if let Some(ref mut prelude) = state.flv_prelude {
+            match media.media_type {
+                MediaType::Video => prelude.has_video = true,
+                MediaType::Audio => prelude.has_audio = true,
+            }
+            if is_sequence_header {
+                prelude.tags.extend(tag);
+                return Ok(None);
+            }
+        }
 
-                    // TODO: check for the actual format, do not just assume
-                    let mut caps = gst::Caps::builder("video/x-flv");
-                        //gst::Caps::builder("video/x-h264").field("stream-format", &"avc");
+        let header = match state.flv_prelude.take() {
+            Some(prelude) => Some(self.flv_header(element, state, prelude)?),
+            None => None,
+        };
 
-                    if let Some(val) = metadata.video_height {
-                        caps = caps.field("height", &val);
-                    }
-                    if let Some(val) = metadata.video_width {
-                        caps = caps.field("width", &val);
-                    }
-                    if let Some(val) = metadata.video_frame_rate {
-                        caps = caps.field("framerate", &val);
+        let dts = state.flv_timeline.running_time(element, media.timestamp);
+        let pts = (dts as i64 + composition_time as i64 * 1_000_000).max(0) as u64;
+        state.flv_last_running_time = Some(dts);
+
+        let is_delta_unit =
+            media.media_type == MediaType::Video && !is_sequence_header && !media.is_keyframe();
+        let mut buffer = self.flv_buffer(element, state, tag);
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_dts(gst::ClockTime::from_nseconds(dts));
+            buffer.set_pts(gst::ClockTime::from_nseconds(pts));
+            if is_delta_unit {
+                buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+            }
+        }
+
+        match header {
+            Some(header) => {
+                state.flv_next = Some(buffer);
+                Ok(Some(header))
+            }
+            None => Ok(Some(buffer)),
+        }
+    }
+
+    /// The file header, announcing the tracks seen so far, followed by the held back tags
+    fn flv_header(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+        prelude: FlvPrelude,
+    ) -> Result<gst::Buffer, gst::FlowError> {
+        element
+            .set_caps(&gst::Caps::new_simple("video/x-flv", &[]))
+            .map_err(|_| gst::FlowError::NotNegotiated)?;
+
+        gst_debug!(
+            CAT,
+            obj: element,
+            "Writing the FLV header, with audio: {}, with video: {}",
+            prelude.has_audio,
+            prelude.has_video
+        );
+        let mut data = flv::file_header(prelude.has_audio, prelude.has_video);
+        data.extend(prelude.tags);
+
+        let mut buffer = self.flv_buffer(element, state, data);
+        buffer
+            .get_mut()
+            .unwrap()
+            .set_flags(gst::BufferFlags::HEADER);
+        Ok(buffer)
+    }
+
+    /// Marks the streams of the channel as discontinuous after media was skipped to reduce the
+    /// latency, and lets the application know
+    fn caught_up(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+        channel: &ChannelKey,
+        skipped: u32,
+    ) {
+        gst_info!(
+            CAT,
+            obj: element,
+            "Skipped {}ms of {} to catch up",
+            skipped,
+            channel
+        );
+
+        match state.mode {
+            Mode::Demux => {
+                if let Some(publisher) = state.publishers.get_mut(channel) {
+                    for stream in publisher
+                        .video
+                        .iter_mut()
+                        .chain(publisher.audio.iter_mut())
+                        .chain(publisher.data.iter_mut())
+                    {
+                        stream.discont = true;
                     }
+                }
+            }
+            Mode::Flv => {
+                if state.flv_publisher.as_ref() == Some(channel) {
+                    state.flv_discont = true;
+                }
+            }
+        }
+
+        let structure = gst::Structure::builder("rtmpsvrsrc-catch-up")
+            .field("app", &channel.app_name)
+            .field("stream-key", &channel.stream_key)
+            .field("skipped", &(skipped as u64 * 1_000_000))
+            .build();
+        let _ = element.post_message(
+            gst::message::Element::builder(structure)
+                .src(Some(element))
+                .build(),
+        );
+    }
+
+    /// Reports the connect and stall timeouts that were reached
+    fn handle_timeouts(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+    ) -> Result<(), gst::FlowError> {
+        let now = Instant::now();
+        let mut is_timed_out = false;
+
+        if let Some(timeout) = state.connect_timeout {
+            if state.waiting_publisher && now >= state.started_at + timeout {
+                state.waiting_publisher = false;
+                is_timed_out = true;
+                gst::element_warning!(
+                    element,
+                    gst::ResourceError::Read,
+                    ["No publisher within {}ms", timeout.as_millis()]
+                );
+            }
+        }
+
+        if let Some(timeout) = state.stall_timeout {
+            let stalled: Vec<ChannelKey> = state
+                .last_media
+                .iter()
+                .filter(|(_, last_media)| now >= **last_media + timeout)
+                .map(|(channel, _)| channel.clone())
+                .collect();
+
+            for channel in stalled {
+                // Reported once, until media is received again
+                state.last_media.remove(&channel);
+                is_timed_out = true;
+                gst::element_warning!(
+                    element,
+                    gst::ResourceError::Read,
+                    [
+                        "No media received from {} for {}ms",
+                        channel,
+                        timeout.as_millis()
+                    ]
+                );
+
+                if state.drop_stalled {
+                    gst_info!(CAT, obj: element, "Disconnecting the publisher of {}", channel);
+                    state.server.disconnect_publisher(channel);
+                }
+            }
+        }
+
+        if is_timed_out && state.eos_on_timeout {
+            gst_info!(CAT, obj: element, "Timed out, sending EOS");
+            for stream in state.publishers.values().flat_map(Publisher::streams) {
+                stream.pad.push_event(gst::event::Eos::new());
+            }
+            return Err(gst::FlowError::Eos);
+        }
+
+        Ok(())
+    }
+
+    /// Joins or starts the server of the settings, and subscribes to the channels they accept
+    fn subscribe(
+        &self,
+        element: &super::RtmpSrvSrc,
+        settings: &Settings,
+    ) -> Result<(Arc<SharedServer>, u64), gst::ErrorMessage> {
+        let tls = tls::optional_server_config(
+            settings.tls_certificate.as_deref(),
+            settings.tls_key.as_deref(),
+        )
+        .map_err(|err| gst::error_msg!(gst::ResourceError::Settings, ["{}", err]))?;
+        let address = format!("{}:{}", settings.address, settings.port);
+        let server = registry::acquire_shared(element.upcast_ref(), &self.context, &address, tls)
+            .map_err(|err| {
+            gst::error_msg!(
+                gst::ResourceError::Busy,
+                ["Failed to bind to address {}: {}", address, err]
+            )
+        })?;
+        gst_info!(
+            CAT,
+            obj: element,
+            "Listening for connections on {}",
+            server.address()
+        );
+
+        self.queue.reset(settings.queue_limits);
+        let subscription_id = server.subscribe(Subscription {
+            config: ServerConfig {
+                app_names: settings.app_names.clone(),
+                stream_keys: settings.stream_keys.clone(),
+                push_targets: settings.push_targets.clone(),
+                secret: settings.secret.clone(),
+                handshake_timeout: timeout_duration(settings.handshake_timeout),
+            },
+            queue: self.queue.clone(),
+            listener: Arc::new(SignalListener {
+                element: element.downgrade(),
+            }),
+        });
 
-                    *video_caps = Some(caps.build());
+        *self.stats_handle.lock().unwrap() = Some(server.stats_handle());
+        Ok((server, subscription_id))
+    }
+
+    /// Replaces the server whose connections thread is gone with a new one. Its publishers are
+    /// gone as well, their pads wait for them to come back.
+    fn restart_server(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+    ) -> Result<(), gst::FlowError> {
+        let channels: Vec<_> = state
+            .publishers
+            .keys()
+            .cloned()
+            .chain(state.flv_publisher.clone())
+            .collect();
+        for channel in channels {
+            self.publish_ended(element, state, channel);
+        }
 
-                    // TODO: check for the actual format, do not just assume acc
-                    let mut caps = gst::Caps::builder("audio/mpeg")
-                        .field("mpegversion", &4i32)
-                        .field("framed", &true)
-                        .field("stream-format", &"raw");
+        state.server.unsubscribe(state.subscription_id);
+        SharedServer::release(state.server.clone());
+        let settings = self.settings.lock().unwrap();
+        let (server, subscription_id) = self.subscribe(element, &settings).map_err(|err| {
+            element.post_error_message(err);
+            gst::FlowError::Error
+        })?;
+        state.server = server;
+        state.subscription_id = subscription_id;
+        Ok(())
+    }
 
-                    if let Some(val) = metadata.audio_channels {
-                        caps = caps.field("channels", &val);
+    fn publish_started(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+        channel: ChannelKey,
+    ) {
+        gst_info!(CAT, obj: element, "Publishing started on {}", channel);
+        state.waiting_publisher = false;
+        state.last_media.insert(channel.clone(), Instant::now());
+
+        match state.mode {
+            Mode::Demux => {
+                if let Some(publisher) = state.publishers.get_mut(&channel) {
+                    if !publisher.is_publishing {
+                        gst_debug!(CAT, obj: element, "Reusing the pads of {}", channel);
+                        publisher.restart();
                     }
-                    if let Some(val) = metadata.audio_sample_rate {
-                        caps = caps.field("rate", &val);
+                    return;
+                }
+
+                // Pads are named after the stream key, unless another application already uses it
+                let name = if state
+                    .publishers
+                    .values()
+                    .any(|publisher| publisher.name == channel.stream_key)
+                {
+                    format!("{}_{}", channel.app_name, channel.stream_key)
+                } else {
+                    channel.stream_key.clone()
+                };
+                state.publishers.insert(channel, Publisher::new(name));
+            }
+            Mode::Flv => match state.flv_publisher {
+                None => state.flv_publisher = Some(channel),
+                Some(ref current) if *current == channel => (),
+                Some(ref current) => gst_warning!(
+                    CAT,
+                    obj: element,
+                    "Ignoring {}, only the content of {} is output in flv mode",
+                    channel,
+                    current
+                ),
+            },
+        }
+    }
+
+    /// Returns whether the flv output ended with the publisher
+    fn publish_ended(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+        channel: ChannelKey,
+    ) -> bool {
+        gst_info!(CAT, obj: element, "Publishing ended on {}", channel);
+        state.last_media.remove(&channel);
+
+        match state.mode {
+            Mode::Demux => {
+                if !state.eos_on_disconnect {
+                    // The pads wait for the publisher to come back
+                    if let Some(publisher) = state.publishers.get_mut(&channel) {
+                        publisher.is_publishing = false;
                     }
+                    return false;
+                }
+
+                let publisher = match state.publishers.remove(&channel) {
+                    Some(publisher) => publisher,
+                    None => return false,
+                };
 
-                    *audio_caps = Some(caps.build());
+                for stream in publisher.streams() {
+                    stream.pad.push_event(gst::event::Eos::new());
+                    let _ = stream.pad.set_active(false);
+                    state.flow_combiner.remove_pad(&stream.pad);
+                    let _ = element.remove_pad(&stream.pad);
+                }
+                self.post_stream_collection(element, state);
+                false
+            }
+            Mode::Flv => {
+                if state.flv_publisher.as_ref() != Some(&channel) {
+                    return false;
                 }
-                RtmpInput::Media(media) => {
-                    // TODO: Decide what to set based on content
-                    if let (MediaType::Video, Some(video_caps)) =
-                        (media.media_type, video_caps.as_ref())
-                    {
-                        gst_info!(CAT, obj: src, "Setting {:?}", video_caps);
-                        src.set_caps(video_caps)
-                            .map_err(|_| gst::FlowError::NotNegotiated)?;
 
-                        // let templ = src.get_element_class().get_pad_template("video").unwrap();
+                // Otherwise the content of the next publisher follows, as a discontinuity
+                state.flv_publisher = None;
+                state.flv_discont = true;
+                state.flv_timestamp_offset = None;
+                let min_running_time = state.flv_last_running_time.map_or(0, |time| time + 1);
+                state.flv_timeline.restart(min_running_time);
+                state.eos_on_disconnect
+            }
+        }
+    }
+
+    fn post_stream_collection(&self, element: &super::RtmpSrvSrc, state: &Started) {
+        let mut collection = gst::StreamCollection::builder(None);
+        for publisher in state.publishers.values() {
+            for stream in publisher.streams() {
+                collection = collection.stream(&stream.stream);
+            }
+        }
+
+        let message = gst::message::StreamCollection::builder(&collection.build())
+            .src(Some(element))
+            .build();
+        let _ = element.post_message(message);
+    }
+
+    /// Creates the pad of the publisher for the media type, if it does not exist yet
+    fn ensure_stream(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+        channel: &ChannelKey,
+        media_type: &MediaType,
+    ) {
+        let publisher = state.publishers.get_mut(channel).unwrap();
+        let (slot, template, prefix, stream_type) = match media_type {
+            MediaType::Video => (
+                &mut publisher.video,
+                "video_%s",
+                "video",
+                gst::StreamType::VIDEO,
+            ),
+            MediaType::Audio => (
+                &mut publisher.audio,
+                "audio_%s",
+                "audio",
+                gst::StreamType::AUDIO,
+            ),
+        };
 
-                        let chunk = media.data;
-                        // Here we return the buffer
-                        let size = chunk.len();
-                        assert_ne!(chunk.len(), 0);
+        if slot.is_some() {
+            return;
+        }
 
-                        let offset = *position;
-                        *position += size as u64;
+        let name = format!("{}_{}", prefix, publisher.name);
+        let mut stream = Stream::new(element, template, &name, stream_type);
+        if let Some(ref properties) = publisher.metadata_properties {
+            stream.set_tags(metadata_tags(properties, media_type));
+        }
+        state.flow_combiner.add_pad(&stream.pad);
+        *slot = Some(stream);
+
+        self.post_stream_collection(element, state);
+    }
 
-                        gst_info!(
+    /// Pushes the data message on the data pad of the publisher, an onMetaData also updates the
+    /// tags of its streams
+    fn handle_data(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+        channel: ChannelKey,
+        message: DataMessage,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        if !state.publishers.contains_key(&channel) {
+            return Ok(gst::FlowSuccess::Ok);
+        }
+
+        if message.name == "onMetaData" {
+            self.update_tags(element, state, &channel, &message);
+        }
+
+        let publisher = state.publishers.get_mut(&channel).unwrap();
+        if publisher.data.is_none() {
+            let name = format!("data_{}", publisher.name);
+            let stream = Stream::new(element, "data_%s", &name, gst::StreamType::UNKNOWN);
+            state.flow_combiner.add_pad(&stream.pad);
+            publisher.data = Some(stream);
+
+            self.post_stream_collection(element, state);
+        }
+
+        let publisher = state.publishers.get_mut(&channel).unwrap();
+        let timestamp = publisher.running_time(element, message.timestamp);
+        let stream = publisher.data.as_mut().unwrap();
+        // Only sent when the pad is new, or after the publisher published again
+        stream.set_caps(gst::Caps::new_simple("application/x-amf0", &[]));
+
+        // The parsed message goes along the payload, in sync with it
+        let values = message
+            .values
+            .iter()
+            .filter_map(amf0_to_send_value)
+            .collect();
+        let structure = gst::Structure::builder("rtmpsvrsrc-data")
+            .field("name", &message.name)
+            .field("running-time", &timestamp)
+            .field("values", &gst::Array::from_owned(values))
+            .build();
+        stream
+            .pad
+            .push_event(gst::event::CustomDownstream::new(structure));
+
+        let mut buffer = gst::Buffer::from_slice(message.payload);
+        stream.set_timestamps(buffer.get_mut().unwrap(), timestamp, timestamp);
+        let res = stream.pad.push(buffer);
+        state.flow_combiner.update_pad_flow(&stream.pad, res)
+    }
+
+    /// Updates the tags of the streams of the publisher with the properties of its onMetaData
+    fn update_tags(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+        channel: &ChannelKey,
+        message: &DataMessage,
+    ) {
+        let publisher = state.publishers.get_mut(channel).unwrap();
+        let properties = match message.values.first() {
+            Some(Amf0Value::Object(properties)) => properties.clone(),
+            _ => {
+                gst_warning!(CAT, obj: element, "Ignoring onMetaData without properties");
+                return;
+            }
+        };
+
+        if let Some(ref mut stream) = publisher.video {
+            stream.set_tags(metadata_tags(&properties, &MediaType::Video));
+        }
+        if let Some(ref mut stream) = publisher.audio {
+            stream.set_tags(metadata_tags(&properties, &MediaType::Audio));
+        }
+        publisher.metadata_properties = Some(properties);
+    }
+
+    fn handle_media(
+        &self,
+        element: &super::RtmpSrvSrc,
+        state: &mut Started,
+        channel: ChannelKey,
+        media: Media,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        if !state.publishers.contains_key(&channel) {
+            self.publish_started(element, state, channel.clone());
+        }
+
+        match media.media_type {
+            MediaType::Video => {
+                let packet = match VideoPacket::parse(&media.data) {
+                    Some(packet) => packet,
+                    None => {
+                        gst_warning!(CAT, obj: element, "Ignoring unsupported video tag");
+                        return Ok(gst::FlowSuccess::Ok);
+                    }
+                };
+
+                self.ensure_stream(element, state, &channel, &media.media_type);
+                let publisher = state.publishers.get_mut(&channel).unwrap();
+                let stream = publisher.video.as_mut().unwrap();
+
+                match packet.packet_type {
+                    VideoPacketType::SequenceHeader => {
+                        let caps =
+                            video_caps(packet.codec, &packet.data, publisher.metadata.as_ref());
+                        gst_info!(CAT, obj: element, "Setting {:?}", caps);
+                        stream.set_caps(caps);
+                        Ok(gst::FlowSuccess::Ok)
+                    }
+                    VideoPacketType::CodedFrames => {
+                        if stream.caps.is_none() {
+                            if packet.codec.requires_sequence_header() {
+                                gst_debug!(CAT, obj: element, "No video sequence header yet");
+                                return Ok(gst::FlowSuccess::Ok);
+                            }
+
+                            let caps = video_caps(
+                                packet.codec,
+                                &Bytes::new(),
+                                publisher.metadata.as_ref(),
+                            );
+                            gst_info!(CAT, obj: element, "Setting {:?}", caps);
+                            stream.set_caps(caps);
+                        }
+
+                        gst_trace!(
                             CAT,
-                            obj: src,
-                            "Chunk of {} bytes received at offset {}",
-                            chunk.len(),
-                            offset
+                            obj: element,
+                            "Video tag of {} bytes received",
+                            packet.data.len()
                         );
 
-                        let mut buffer = gst::Buffer::from_slice(chunk);
+                        let dts = publisher.running_time(element, media.timestamp);
+                        let pts = (dts as i64 + packet.composition_time as i64 * 1_000_000).max(0);
 
+                        let stream = publisher.video.as_mut().unwrap();
+                        let mut buffer = gst::Buffer::from_slice(packet.data);
                         {
                             let buffer = buffer.get_mut().unwrap();
-                            buffer.set_offset(offset);
-                            buffer.set_offset_end(offset + size as u64);
+                            stream.set_timestamps(buffer, dts, pts as u64);
+                            if !packet.is_keyframe {
+                                buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+                            }
                         }
 
-                        return Ok(buffer);
+                        let res = stream.pad.push(buffer);
+                        state.flow_combiner.update_pad_flow(&stream.pad, res)
+                    }
+                    VideoPacketType::EndOfSequence | VideoPacketType::Mpeg2TsSequenceStart => {
+                        Ok(gst::FlowSuccess::Ok)
                     }
-                    // TODO: Handle Audio content
                 }
             }
-        }
+            MediaType::Audio => {
+                let packet = match AudioPacket::parse(&media.data) {
+                    Some(packet) => packet,
+                    None => {
+                        gst_warning!(CAT, obj: element, "Ignoring unsupported audio tag");
+                        return Ok(gst::FlowSuccess::Ok);
+                    }
+                };
 
-        gst::element_error!(src, gst::LibraryError::TooLazy, ["No content yet"]);
-        return Err(gst::FlowError::Error);
-    }
-}
+                self.ensure_stream(element, state, &channel, &media.media_type);
+                let publisher = state.publishers.get_mut(&channel).unwrap();
+
+                match packet.packet_type {
+                    AudioPacketType::SequenceHeader | AudioPacketType::MultichannelConfig => {
+                        if packet.packet_type == AudioPacketType::SequenceHeader {
+                            publisher.audio_config = Some((packet.codec, packet.data));
+                        } else {
+                            publisher.audio_channels = packet.channels;
+                        }
+
+                        // The channel layout alone is not enough to decode some of the codecs
+                        let has_config = publisher
+                            .audio_config
+                            .as_ref()
+                            .map_or(false, |(codec, _)| *codec == packet.codec);
+                        if has_config || !packet.codec.requires_sequence_header() {
+                            let caps =
+                                publisher.audio_caps(packet.codec, packet.rate, packet.channels);
+                            gst_info!(CAT, obj: element, "Setting {:?}", caps);
+                            publisher.audio.as_mut().unwrap().set_caps(caps);
+                        }
+                        Ok(gst::FlowSuccess::Ok)
+                    }
+                    AudioPacketType::CodedFrames => {
+                        if publisher.audio.as_ref().unwrap().caps.is_none() {
+                            if packet.codec.requires_sequence_header() {
+                                gst_debug!(CAT, obj: element, "No audio sequence header yet");
+                                return Ok(gst::FlowSuccess::Ok);
+                            }
 
-/// Accepts TCP connections
-fn accept_connections(connection_sender: Sender<TcpStream>, listener: TcpListener) {
-    println!("Listening for connections...");
-    for stream in listener.incoming() {
-        println!("New connection!");
-        match connection_sender.send(stream.unwrap()) {
-            Ok(_) => (),
-            Err(error) => panic!("Error sending stream to connection handler: {:?}", error),
+                            let caps =
+                                publisher.audio_caps(packet.codec, packet.rate, packet.channels);
+                            gst_info!(CAT, obj: element, "Setting {:?}", caps);
+                            publisher.audio.as_mut().unwrap().set_caps(caps);
+                        }
+
+                        gst_trace!(
+                            CAT,
+                            obj: element,
+                            "Audio tag of {} bytes received",
+                            packet.data.len()
+                        );
+
+                        let timestamp = publisher.running_time(element, media.timestamp);
+
+                        let stream = publisher.audio.as_mut().unwrap();
+                        let mut buffer = gst::Buffer::from_slice(packet.data);
+                        stream.set_timestamps(buffer.get_mut().unwrap(), timestamp, timestamp);
+
+                        let res = stream.pad.push(buffer);
+                        state.flow_combiner.update_pad_flow(&stream.pad, res)
+                    }
+                    AudioPacketType::EndOfSequence => Ok(gst::FlowSuccess::Ok),
+                }
+            }
         }
     }
 }
 
-/// Handle the lifecycle of all TCP connections by sending and receiving data
-fn handle_connections(media_sink: Sender<RtmpInput>, connection_receiver: Receiver<TcpStream>) {
-    let mut connections = Slab::new();
-    let mut connection_ids = HashSet::new();
-    let mut server = Server::new(media_sink);
+impl PushSrcImpl for RtmpSvrSrc {
+    fn create(&self, src: &Self::Type) -> Result<gst::Buffer, gst::FlowError> {
+        let mut is_src_pad_unused = false;
+        {
+            let mut state = self.state.lock().unwrap();
+            let state = match *state {
+                State::Stopped => {
+                    gst::element_error!(src, gst::LibraryError::Failed, ["Not started yet"]);
+                    return Err(gst::FlowError::Error);
+                }
+                State::Started(ref mut state) => state,
+            };
 
-    loop {
-        match connection_receiver.try_recv() {
-            Err(TryRecvError::Disconnected) => panic!("Connection receiver closed"),
-            Err(TryRecvError::Empty) => (),
-            Ok(stream) => {
-                let connection = Connection::new(stream);
-                let id = connections.insert(connection);
-                let connection = connections.get_mut(id).unwrap();
-                connection.connection_id = Some(id);
-                connection_ids.insert(id);
+            if let Some(buffer) = state.flv_next.take() {
+                return Ok(buffer);
+            }
 
-                println!("Connection {} started", id);
+            if state.mode == Mode::Demux && !state.src_pad_done {
+                state.src_pad_done = true;
+                is_src_pad_unused = true;
             }
         }
 
-        let mut ids_to_clear = Vec::new();
-        let mut packets_to_write = Vec::new();
-        for connection_id in &connection_ids {
-            let connection = connections.get_mut(*connection_id).unwrap();
-            match connection.read() {
-                Err(ConnectionError::SocketClosed) => {
-                    println!("Socket closed for id {}", connection_id);
-                    ids_to_clear.push(*connection_id);
+        // The base class sent the stream start and the caps already, an element linked to the
+        // src pad by mistake gets the end of its stream instead of waiting for data forever
+        if is_src_pad_unused {
+            gst_debug!(CAT, obj: src, "Ending the src pad, unused in demux mode");
+            let pad = src.get_static_pad("src").unwrap();
+            let segment = gst::FormattedSegment::<gst::format::Bytes>::new();
+            pad.push_event(gst::event::Segment::new(&segment));
+            pad.push_event(gst::event::Eos::new());
+        }
+
+        // In demux mode the content is pushed on the sometimes pads, so we only return from here
+        // when streaming has to stop
+        loop {
+            let deadline = match *self.state.lock().unwrap() {
+                State::Started(ref state) => state.timeout_deadline(),
+                State::Stopped => None,
+            };
+
+            // Wait without holding the state lock, unlock() wakes us up
+            let input = match self.queue.pop(deadline) {
+                Ok(input) => {
+                    self.post_drops(src);
+                    input
                 }
+                Err(QueueError::Timeout) => {
+                    let mut state = self.state.lock().unwrap();
+                    if let State::Started(ref mut state) = *state {
+                        self.handle_timeouts(src, state)?;
+                    }
+                    continue;
+                }
+                Err(QueueError::Flushing) => {
+                    gst_debug!(CAT, obj: src, "Flushing");
+                    return Err(gst::FlowError::Flushing);
+                }
+                Err(QueueError::Closed) => {
+                    let mut state = self.state.lock().unwrap();
+                    let state = match *state {
+                        State::Stopped => return Err(gst::FlowError::Flushing),
+                        State::Started(ref mut state) => state,
+                    };
+                    if state.eos_on_disconnect {
+                        return Err(gst::FlowError::Eos);
+                    }
 
-                Err(error) => {
-                    println!(
-                        "I/O error while reading connection {}: {:?}",
-                        connection_id, error
+                    // The pipeline is kept running, waiting for the next publisher
+                    gst::element_warning!(
+                        src,
+                        gst::ResourceError::Read,
+                        ["The server stopped receiving content, restarting it"]
                     );
-                    ids_to_clear.push(*connection_id);
+                    self.restart_server(src, state)?;
+                    continue;
                 }
+            };
 
-                Ok(result) => match result {
-                    ReadResult::NoBytesReceived => (),
-                    ReadResult::HandshakingInProgress => (),
-                    ReadResult::BytesReceived { buffer, byte_count } => {
-                        let mut server_results =
-                            match server.bytes_received(*connection_id, &buffer[..byte_count]) {
-                                Ok(results) => results,
-                                Err(error) => {
-                                    println!("Input caused the following server error: {}", error);
-                                    ids_to_clear.push(*connection_id);
-                                    continue;
-                                }
-                            };
-
-                        for result in server_results.drain(..) {
-                            match result {
-                                ServerResult::OutboundPacket {
-                                    target_connection_id,
-                                    packet,
-                                } => {
-                                    packets_to_write.push((target_connection_id, packet));
-                                }
+            let mut state = self.state.lock().unwrap();
+            let state = match *state {
+                State::Stopped => return Err(gst::FlowError::Flushing),
+                State::Started(ref mut state) => state,
+            };
+
+            for (channel, skipped) in self.queue.take_skips() {
+                self.caught_up(src, state, &channel, skipped);
+            }
+
+            match input {
+                RtmpInput::PublishStarted(channel) => self.publish_started(src, state, channel),
+                RtmpInput::PublishEnded(channel) => {
+                    if self.publish_ended(src, state, channel) {
+                        return Err(gst::FlowError::Eos);
+                    }
+                }
+                RtmpInput::PushStatus(channel, url, status) => {
+                    self.post_push_status(src, &channel, &url, &status)
+                }
+                RtmpInput::Metadata(channel, metadata) => {
+                    gst_debug!(CAT, obj: src, "Metadata for {}: {:?}", channel, metadata);
 
-                                ServerResult::DisconnectConnection {
-                                    connection_id: id_to_close,
-                                } => {
-                                    ids_to_clear.push(id_to_close);
+                    match state.mode {
+                        Mode::Demux => {
+                            if let Some(publisher) = state.publishers.get_mut(&channel) {
+                                publisher.metadata = Some(metadata);
+                            }
+                        }
+                        Mode::Flv => {
+                            if state.flv_publisher.as_ref() != Some(&channel) {
+                                continue;
+                            }
+
+                            match flv::metadata_tag_body(&metadata) {
+                                Ok(body) => {
+                                    let tag = flv::tag(flv::TAG_TYPE_SCRIPT_DATA, 0, &body);
+                                    if let Some(ref mut prelude) = state.flv_prelude {
+                                        prelude.has_video |= metadata.video_codec.is_some();
+                                        prelude.has_audio |= metadata.audio_codec.is_some();
+                                        prelude.tags.extend(tag);
+                                        continue;
+                                    }
+                                    return Ok(self.flv_buffer(src, state, tag));
                                 }
+                                Err(error) => gst_warning!(
+                                    CAT,
+                                    obj: src,
+                                    "Failed to serialize metadata: {}",
+                                    error
+                                ),
                             }
                         }
                     }
-                },
+                }
+                RtmpInput::Data(channel, message) => {
+                    gst_debug!(CAT, obj: src, "Data message for {}: {:?}", channel, message);
+
+                    if state.mode == Mode::Demux {
+                        self.handle_data(src, state, channel, message)?;
+                    }
+                }
+                RtmpInput::Media(channel, media) => {
+                    state.last_media.insert(channel.clone(), Instant::now());
+                    match state.mode {
+                        Mode::Demux => {
+                            self.handle_media(src, state, channel, media)?;
+                        }
+                        Mode::Flv => {
+                            if state.flv_publisher.as_ref() != Some(&channel) {
+                                continue;
+                            }
+
+                            if let Some(buffer) = self.flv_media(src, state, media)? {
+                                return Ok(buffer);
+                            }
+                        }
+                    }
+                }
             }
         }
+    }
+}
+
+/// Maps the onMetaData properties to the tags of a stream, the ones without a matching tag are
+/// kept as extended comments
+fn metadata_tags(properties: &HashMap<String, Amf0Value>, media_type: &MediaType) -> gst::TagList {
+    let mut names: Vec<_> = properties.keys().collect();
+    names.sort();
 
-        for (connection_id, packet) in packets_to_write.drain(..) {
-            let connection = connections.get_mut(connection_id).unwrap();
-            connection.write(packet.bytes);
+    let mut tags = gst::TagList::new();
+    {
+        let tags = tags.get_mut().unwrap();
+        tags.set_scope(gst::TagScope::Stream);
+
+        for name in names {
+            let value = match properties[name] {
+                Amf0Value::Utf8String(ref value) => value.clone(),
+                Amf0Value::Number(value) => value.to_string(),
+                Amf0Value::Boolean(value) => value.to_string(),
+                _ => continue,
+            };
+            let mode = gst::TagMergeMode::Append;
+
+            match (name.as_str(), media_type) {
+                ("encoder", _) => tags.add::<gst::tags::Encoder>(&value.as_str(), mode),
+                ("title", _) => tags.add::<gst::tags::Title>(&value.as_str(), mode),
+                ("author", _) | ("artist", _) => {
+                    tags.add::<gst::tags::Artist>(&value.as_str(), mode)
+                }
+                ("copyright", _) => tags.add::<gst::tags::Copyright>(&value.as_str(), mode),
+                ("comment", _) | ("description", _) => {
+                    tags.add::<gst::tags::Comment>(&value.as_str(), mode)
+                }
+                ("album", _) => tags.add::<gst::tags::Album>(&value.as_str(), mode),
+                ("genre", _) => tags.add::<gst::tags::Genre>(&value.as_str(), mode),
+                // Bitrates are announced in kbps
+                ("videodatarate", MediaType::Video) | ("audiodatarate", MediaType::Audio) => {
+                    if let Ok(kbps) = value.parse::<f64>() {
+                        tags.add::<gst::tags::Bitrate>(&((kbps * 1000.0) as u32), mode);
+                    }
+                }
+                ("videodatarate", _) | ("audiodatarate", _) => (),
+                _ => tags.add::<gst::tags::ExtendedComment>(
+                    &format!("{}={}", name, value).as_str(),
+                    mode,
+                ),
+            }
         }
+    }
+
+    tags
+}
 
-        for closed_id in ids_to_clear {
-            println!("Connection {} closed", closed_id);
-            connection_ids.remove(&closed_id);
-            connections.remove(closed_id);
-            server.notify_connection_closed(closed_id);
+/// AMF0 objects become structures, the values without an equivalent are left out
+fn amf0_to_send_value(value: &Amf0Value) -> Option<glib::SendValue> {
+    match value {
+        Amf0Value::Number(value) => Some(value.to_send_value()),
+        Amf0Value::Boolean(value) => Some(value.to_send_value()),
+        Amf0Value::Utf8String(value) => Some(value.to_send_value()),
+        Amf0Value::Object(properties) => {
+            let mut structure = gst::Structure::new_empty("object");
+            let mut names: Vec<_> = properties.keys().collect();
+            names.sort();
+            for name in names {
+                if let Some(value) = amf0_to_send_value(&properties[name]) {
+                    structure.set_value(name, value);
+                }
+            }
+            Some(structure.to_send_value())
         }
+        _ => None,
     }
 }
+
+fn string_list(array: Option<&gst::Array>) -> Vec<String> {
+    array
+        .map(|array| array.as_slice())
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|value| value.get::<String>().expect("type checked upstream"))
+        .collect()
+}
+
+fn string_array(list: &[String]) -> gst::Array<'static> {
+    gst::Array::from_owned(list.iter().map(|value| value.to_send_value()).collect())
+}
+
+/// Timeouts are set in milliseconds, with 0 disabling them
+fn timeout_duration(milliseconds: u32) -> Option<Duration> {
+    match milliseconds {
+        0 => None,
+        milliseconds => Some(Duration::from_millis(milliseconds as u64)),
+    }
+}
+
+fn video_codec_structure(codec: VideoCodec) -> gst::Structure {
+    match codec {
+        VideoCodec::Avc => gst::Structure::builder("video/x-h264")
+            .field("stream-format", &"avc")
+            .field("alignment", &"au")
+            .build(),
+        VideoCodec::Hevc => gst::Structure::builder("video/x-h265")
+            .field("stream-format", &"hvc1")
+            .field("alignment", &"au")
+            .build(),
+        VideoCodec::Av1 => gst::Structure::builder("video/x-av1")
+            .field("stream-format", &"obu-stream")
+            .field("alignment", &"tu")
+            .build(),
+        VideoCodec::Vp9 => gst::Structure::new_empty("video/x-vp9"),
+        VideoCodec::SorensonH263 => gst::Structure::builder("video/x-flash-video")
+            .field("flvversion", &1i32)
+            .build(),
+        VideoCodec::ScreenVideo => gst::Structure::new_empty("video/x-flash-screen"),
+        VideoCodec::ScreenVideo2 => gst::Structure::new_empty("video/x-flash-screen2"),
+        VideoCodec::Vp6 => gst::Structure::new_empty("video/x-vp6-flash"),
+        VideoCodec::Vp6Alpha => gst::Structure::new_empty("video/x-vp6-alpha"),
+    }
+}
+
+fn video_caps(
+    codec: VideoCodec,
+    codec_data: &Bytes,
+    metadata: Option<&StreamMetadata>,
+) -> gst::Caps {
+    let mut s = video_codec_structure(codec);
+    // The VP9 configuration record has no equivalent in the caps of the decoders
+    if !codec_data.is_empty() && codec != VideoCodec::Vp9 {
+        s.set("codec_data", &gst::Buffer::from_slice(codec_data.clone()));
+    }
+
+    if let Some(metadata) = metadata {
+        if let Some(val) = metadata.video_width {
+            s.set("width", &(val as i32));
+        }
+        if let Some(val) = metadata.video_height {
+            s.set("height", &(val as i32));
+        }
+        if let Some(val) = metadata
+            .video_frame_rate
+            .and_then(gst::Fraction::approximate_f32)
+        {
+            s.set("framerate", &val);
+        }
+    }
+
+    // The configuration record describes the stream better than the metadata of the publisher
+    let config = match codec {
+        VideoCodec::Avc => AvcConfig::parse(codec_data),
+        _ => None,
+    };
+    if let Some(config) = config {
+        s.set("profile", &config.profile);
+        s.set("level", &config.level);
+        s.set("width", &(config.width as i32));
+        s.set("height", &(config.height as i32));
+        if let Some((numerator, denominator)) = config.framerate {
+            s.set("framerate", &gst::Fraction::new(numerator, denominator));
+        }
+        if let Some((numerator, denominator)) = config.pixel_aspect_ratio {
+            s.set(
+                "pixel-aspect-ratio",
+                &gst::Fraction::new(numerator, denominator),
+            );
+        }
+    }
+
+    let mut caps = gst::Caps::new_empty();
+    caps.get_mut().unwrap().append_structure(s);
+    caps
+}
+
+fn audio_codec_structure(codec: AudioCodec) -> gst::Structure {
+    match codec {
+        AudioCodec::Aac => gst::Structure::builder("audio/mpeg")
+            .field("mpegversion", &4i32)
+            .field("stream-format", &"raw")
+            .build(),
+        AudioCodec::Opus => gst::Structure::new_empty("audio/x-opus"),
+        AudioCodec::Flac => gst::Structure::builder("audio/x-flac")
+            .field("framed", &true)
+            .build(),
+        AudioCodec::Ac3 => gst::Structure::builder("audio/x-ac3")
+            .field("framed", &true)
+            .build(),
+        AudioCodec::Eac3 => gst::Structure::builder("audio/x-eac3")
+            .field("framed", &true)
+            .build(),
+        AudioCodec::Mp3 => gst::Structure::builder("audio/mpeg")
+            .field("mpegversion", &1i32)
+            .field("layer", &3i32)
+            .build(),
+        AudioCodec::Pcm(width) => gst::Structure::builder("audio/x-raw")
+            .field("format", &if width == 8 { "U8" } else { "S16LE" })
+            .field("layout", &"interleaved")
+            .build(),
+        AudioCodec::Adpcm => gst::Structure::builder("audio/x-adpcm")
+            .field("layout", &"swf")
+            .build(),
+        AudioCodec::Nellymoser => gst::Structure::new_empty("audio/x-nellymoser"),
+        AudioCodec::ALaw => gst::Structure::new_empty("audio/x-alaw"),
+        AudioCodec::MuLaw => gst::Structure::new_empty("audio/x-mulaw"),
+        AudioCodec::Speex => gst::Structure::new_empty("audio/x-speex"),
+    }
+}
+
+fn audio_caps(
+    codec: AudioCodec,
+    config: &Bytes,
+    rate: Option<u32>,
+    channels: Option<u8>,
+    metadata: Option<&StreamMetadata>,
+) -> gst::Caps {
+    let mut s = audio_codec_structure(codec);
+
+    if let Some(metadata) = metadata {
+        if let Some(val) = metadata.audio_channels {
+            s.set("channels", &(val as i32));
+        }
+        if let Some(val) = metadata.audio_sample_rate {
+            s.set("rate", &(val as i32));
+        }
+    }
+    if let Some(val) = rate {
+        s.set("rate", &(val as i32));
+    }
+    if let Some(val) = channels {
+        s.set("channels", &(val as i32));
+    }
+
+    match codec {
+        AudioCodec::Aac => {
+            s.set("framed", &true);
+            s.set("codec_data", &gst::Buffer::from_slice(config.clone()));
+            if let Some(config) = AacConfig::parse(config) {
+                s.set("rate", &(config.rate as i32));
+                if let Some(channels) = config.channels {
+                    s.set("channels", &(channels as i32));
+                }
+                if let Some(profile) = config.profile {
+                    s.set("profile", &profile);
+                }
+            }
+        }
+        AudioCodec::Opus => {
+            // Opus is always decoded at 48kHz, whatever the rate of the input was
+            s.set("rate", &48_000i32);
+            match opus_stream_header(config) {
+                Some((channels, mapping_family, header)) => {
+                    s.set("channels", &(channels as i32));
+                    s.set("channel-mapping-family", &(mapping_family as i32));
+                    s.set("streamheader", &header);
+                }
+                None => s.set("channel-mapping-family", &0i32),
+            }
+        }
+        AudioCodec::Flac => {
+            if let Some((rate, channels, header)) = flac_stream_header(config) {
+                s.set("rate", &(rate as i32));
+                s.set("channels", &(channels as i32));
+                s.set("streamheader", &header);
+            }
+        }
+        AudioCodec::Speex => s.set("streamheader", &speex_stream_header()),
+        _ => (),
+    }
+
+    let mut caps = gst::Caps::new_empty();
+    caps.get_mut().unwrap().append_structure(s);
+    caps
+}
+
+/// The OpusHead of the sequence start, followed by an empty OpusTags as expected by the decoder
+fn opus_stream_header(config: &Bytes) -> Option<(u8, u8, gst::Array<'static>)> {
+    if config.len() < 19 || &config[..8] != b"OpusHead" {
+        return None;
+    }
+
+    let vendor = b"gst-rtmpsrv";
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&0u32.to_le_bytes());
+
+    let header = gst::Array::from_owned(vec![
+        stream_header_buffer(config.to_vec()),
+        stream_header_buffer(tags),
+    ]);
+    Some((config[9], config[18], header))
+}
+
+/// Maps the FLAC metadata blocks of the sequence start to the stream headers of flacdec, the first
+/// one being the STREAMINFO block behind the Ogg FLAC mapping header
+fn flac_stream_header(config: &Bytes) -> Option<(u32, u8, gst::Array<'static>)> {
+    let mut blocks = if config.starts_with(b"fLaC") {
+        &config[4..]
+    } else {
+        &config[..]
+    };
+
+    let mut headers = Vec::new();
+    while blocks.len() >= 4 {
+        let length = 4 + u32::from_be_bytes([0, blocks[1], blocks[2], blocks[3]]) as usize;
+        if blocks.len() < length {
+            break;
+        }
+        headers.push(&blocks[..length]);
+        blocks = &blocks[length..];
+    }
+
+    // The STREAMINFO block comes first, its 34 bytes hold the rate and channels after the sizes
+    let stream_info = headers.first()?;
+    if stream_info[0] & 0x7f != 0 || stream_info.len() < 4 + 34 {
+        return None;
+    }
+    let rate = u32::from_be_bytes([0, stream_info[14], stream_info[15], stream_info[16]]) >> 4;
+    let channels = ((stream_info[16] >> 1) & 0x07) + 1;
+
+    let mut first = vec![0x7f];
+    first.extend_from_slice(b"FLAC");
+    first.extend_from_slice(&[1, 0]);
+    first.extend_from_slice(&((headers.len() - 1) as u16).to_be_bytes());
+    first.extend_from_slice(b"fLaC");
+    first.extend_from_slice(stream_info);
+
+    let mut buffers = vec![stream_header_buffer(first)];
+    buffers.extend(
+        headers[1..]
+            .iter()
+            .map(|header| stream_header_buffer(header.to_vec())),
+    );
+    Some((rate, channels, gst::Array::from_owned(buffers)))
+}
+
+/// FLV carries wideband mono Speex without headers, so the ones of the decoder are made up
+fn speex_stream_header() -> gst::Array<'static> {
+    let mut header = b"Speex   ".to_vec();
+    let mut version = b"1.2".to_vec();
+    version.resize(20, 0);
+    header.extend_from_slice(&version);
+    // Version, header size, rate, wideband mode, bitstream version, channels, bitrate (unknown),
+    // frame size, no VBR, frames per packet, extra headers and two reserved fields
+    for value in &[1i32, 80, 16_000, 1, 4, 1, -1, 320, 0, 1, 0, 0, 0] {
+        header.extend_from_slice(&value.to_le_bytes());
+    }
+
+    let vendor = b"gst-rtmpsrv";
+    let mut comments = (vendor.len() as u32).to_le_bytes().to_vec();
+    comments.extend_from_slice(vendor);
+    comments.extend_from_slice(&0u32.to_le_bytes());
+
+    gst::Array::from_owned(vec![
+        stream_header_buffer(header),
+        stream_header_buffer(comments),
+    ])
+}
+
+fn stream_header_buffer(data: Vec<u8>) -> glib::SendValue {
+    let mut buffer = gst::Buffer::from_mut_slice(data);
+    buffer
+        .get_mut()
+        .unwrap()
+        .set_flags(gst::BufferFlags::HEADER);
+    buffer.to_send_value()
+}
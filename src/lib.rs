@@ -1,9 +1,18 @@
 use glib::prelude::*;
 
+mod auth;
+mod codec;
 mod connection;
 mod data;
+mod flv;
 mod imp;
+mod queue;
+mod registry;
+mod relay;
 mod server;
+mod sink;
+mod stats;
+mod tls;
 
 glib::wrapper! {
     pub struct RtmpSrvSrc(ObjectSubclass<imp::RtmpSvrSrc>) @extends gst_base::PushSrc, gst_base::BaseSrc, gst::Element, gst::Object;
@@ -12,6 +21,13 @@ glib::wrapper! {
 unsafe impl Send for RtmpSrvSrc {}
 unsafe impl Sync for RtmpSrvSrc {}
 
+glib::wrapper! {
+    pub struct RtmpSrvSink(ObjectSubclass<sink::RtmpSvrSink>) @extends gst::Element, gst::Object;
+}
+
+unsafe impl Send for RtmpSrvSink {}
+unsafe impl Sync for RtmpSrvSink {}
+
 pub fn plugin_init(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
     gst::Element::register(
         Some(plugin),
@@ -19,6 +35,12 @@ pub fn plugin_init(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
         gst::Rank::None,
         RtmpSrvSrc::static_type(),
     )?;
+    gst::Element::register(
+        Some(plugin),
+        "rtmpsrvsink",
+        gst::Rank::None,
+        RtmpSrvSink::static_type(),
+    )?;
 
     Ok(())
 }
@@ -0,0 +1,681 @@
+use crate::data::{ChannelKey, Media, MediaType, RtmpInput};
+use glib::GEnum;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueueError {
+    /// The queue was set to flushing, the caller should stop waiting for content
+    Flushing,
+    /// No more content will be pushed into the queue
+    Closed,
+    /// Nothing was pushed before the deadline
+    Timeout,
+}
+
+/// Which media goes when the queue is full
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, GEnum)]
+#[repr(u32)]
+#[genum(type_name = "GstRtmpSvrSrcLeaky")]
+pub enum Leaky {
+    #[genum(name = "Upstream: Drop the media being queued", nick = "upstream")]
+    Upstream = 1,
+    #[genum(name = "Downstream: Drop the oldest queued media", nick = "downstream")]
+    Downstream = 2,
+}
+
+/// Limits of the queued media, 0 meaning unlimited. The server thread never waits for room, so
+/// media is dropped once a limit is reached.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueLimits {
+    pub max_buffers: u32,
+    pub max_bytes: u32,
+    /// Distance in milliseconds between the oldest and the newest media of a channel
+    pub max_time: u32,
+    pub leaky: Leaky,
+    /// Milliseconds the oldest media of a channel can lag behind its newest one before skipping to
+    /// its latest keyframe
+    pub max_latency: u32,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            max_buffers: 0,
+            max_bytes: 0,
+            max_time: 0,
+            leaky: Leaky::Downstream,
+            max_latency: 0,
+        }
+    }
+}
+
+/// Media counts of a channel
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DropCounts {
+    /// Queued media
+    pub processed: u64,
+    pub dropped: u64,
+    /// Dropped since the counts were last taken
+    pub pending: u64,
+}
+
+/// Queued media of a channel, looked up in the queue by sequence number
+#[derive(Default)]
+struct ChannelMedia {
+    /// Sequence numbers and timestamps of the media, oldest first
+    queued: VecDeque<(u64, u32)>,
+    /// Sequence number of the latest queued keyframe
+    keyframe: Option<u64>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// Content in push order, the media removed behind the front leaving a hole until it reaches
+    /// the front
+    items: VecDeque<Option<RtmpInput>>,
+    /// Sequence number of the front item
+    front_seq: u64,
+    /// Media of each channel, so that the limits are checked without looking through the queue
+    channels: HashMap<ChannelKey, ChannelMedia>,
+    flushing: bool,
+    closed: bool,
+    limits: QueueLimits,
+    /// Media buffers and bytes in the queue
+    buffers: u32,
+    bytes: u64,
+    /// Channels whose video is dropped until the next keyframe, as it depends on a dropped frame
+    waiting_keyframe: HashSet<ChannelKey>,
+    counts: HashMap<ChannelKey, DropCounts>,
+    /// Milliseconds skipped by the catch ups not taken yet
+    skips: Vec<(ChannelKey, u32)>,
+}
+
+impl QueueState {
+    fn is_over_size(&self, media: &Media) -> bool {
+        let limits = &self.limits;
+        (limits.max_buffers > 0 && self.buffers >= limits.max_buffers)
+            || (limits.max_bytes > 0
+                && self.bytes + media.data.len() as u64 > limits.max_bytes as u64)
+    }
+
+    fn is_over_time(&self, channel: &ChannelKey, media: &Media) -> bool {
+        if self.limits.max_time == 0 {
+            return false;
+        }
+
+        match self.oldest_timestamp(channel) {
+            // RTMP timestamps wrap around
+            Some(oldest) => {
+                media.timestamp.wrapping_sub(oldest) as i32 as i64 > self.limits.max_time as i64
+            }
+            None => false,
+        }
+    }
+
+    fn is_full(&self, channel: &ChannelKey, media: &Media) -> bool {
+        self.is_over_size(media) || self.is_over_time(channel, media)
+    }
+
+    fn oldest_timestamp(&self, channel: &ChannelKey) -> Option<u32> {
+        let channel_media = self.channels.get(channel)?;
+        channel_media
+            .queued
+            .front()
+            .map(|&(_, timestamp)| timestamp)
+    }
+
+    /// Whether the video of the channel is dropped until the next keyframe
+    fn is_waiting_keyframe(&mut self, channel: &ChannelKey, media: &Media) -> bool {
+        if !self.waiting_keyframe.contains(channel) {
+            return false;
+        }
+
+        match media.media_type {
+            MediaType::Video if media.is_keyframe() => {
+                self.waiting_keyframe.remove(channel);
+                false
+            }
+            MediaType::Video => media.can_be_dropped,
+            MediaType::Audio => false,
+        }
+    }
+
+    fn record_drop(&mut self, channel: &ChannelKey, media: &Media) {
+        let counts = self.counts.entry(channel.clone()).or_default();
+        counts.dropped += 1;
+        counts.pending += 1;
+
+        if let MediaType::Video = media.media_type {
+            self.waiting_keyframe.insert(channel.clone());
+        }
+    }
+
+    fn media(&self, seq: u64) -> Option<&Media> {
+        let index = seq.checked_sub(self.front_seq)? as usize;
+        match self.items.get(index) {
+            Some(&Some(RtmpInput::Media(_, ref media))) => Some(media),
+            _ => None,
+        }
+    }
+
+    fn push_back(&mut self, input: RtmpInput) {
+        let seq = self.front_seq + self.items.len() as u64;
+        if let RtmpInput::Media(ref channel, ref media) = input {
+            let channel_media = self.channels.entry(channel.clone()).or_default();
+            channel_media.queued.push_back((seq, media.timestamp));
+            if media.is_keyframe() {
+                channel_media.keyframe = Some(seq);
+            }
+
+            self.buffers += 1;
+            self.bytes += media.data.len() as u64;
+        }
+
+        self.items.push_back(Some(input));
+    }
+
+    fn remove(&mut self, seq: u64) -> Option<RtmpInput> {
+        let index = seq.checked_sub(self.front_seq)? as usize;
+        let input = self.items.get_mut(index)?.take()?;
+        while let Some(None) = self.items.front() {
+            self.items.pop_front();
+            self.front_seq += 1;
+        }
+
+        if let RtmpInput::Media(ref channel, ref media) = input {
+            self.buffers -= 1;
+            self.bytes -= media.data.len() as u64;
+
+            let is_empty = match self.channels.get_mut(channel) {
+                Some(channel_media) => {
+                    // Media is mostly removed from the front of its channel
+                    if let Some(position) = channel_media
+                        .queued
+                        .iter()
+                        .position(|&(queued, _)| queued == seq)
+                    {
+                        channel_media.queued.remove(position);
+                    }
+                    if channel_media.keyframe == Some(seq) {
+                        channel_media.keyframe = None;
+                    }
+                    channel_media.queued.is_empty()
+                }
+                None => false,
+            };
+            if is_empty {
+                self.channels.remove(channel);
+            }
+        }
+        Some(input)
+    }
+
+    fn pop_front(&mut self) -> Option<RtmpInput> {
+        self.remove(self.front_seq)
+    }
+
+    /// Oldest droppable media of the queue, only the sequence headers and the other content
+    /// being kept in front of it
+    fn oldest_droppable(&self) -> Option<u64> {
+        self.items
+            .iter()
+            .zip(self.front_seq..)
+            .find_map(|(item, seq)| match *item {
+                Some(RtmpInput::Media(_, ref media)) if media.can_be_dropped => Some(seq),
+                _ => None,
+            })
+    }
+
+    fn oldest_droppable_of(&self, channel: &ChannelKey) -> Option<u64> {
+        let channel_media = self.channels.get(channel)?;
+        channel_media
+            .queued
+            .iter()
+            .map(|&(seq, _)| seq)
+            .find(|&seq| self.media(seq).map_or(false, |media| media.can_be_dropped))
+    }
+
+    /// Drops the oldest droppable media until there is room for the media of the channel, only
+    /// looking at the channel itself when its time limit is the one reached
+    fn drop_oldest(&mut self, channel: &ChannelKey, media: &Media) {
+        loop {
+            let seq = if self.is_over_size(media) {
+                self.oldest_droppable()
+            } else if self.is_over_time(channel, media) {
+                self.oldest_droppable_of(channel)
+            } else {
+                return;
+            };
+            let seq = match seq {
+                Some(seq) => seq,
+                None => return,
+            };
+
+            if let Some(RtmpInput::Media(key, dropped)) = self.remove(seq) {
+                self.record_drop(&key, &dropped);
+                if let MediaType::Video = dropped.media_type {
+                    self.drop_until_keyframe(&key, seq);
+                }
+            }
+        }
+    }
+
+    /// Skips the media of every channel up to its latest queued keyframe, when its oldest media
+    /// lags behind its newest one by more than the max latency. Sequence headers and the other
+    /// content are kept.
+    fn catch_up(&mut self) {
+        if self.limits.max_latency == 0 {
+            return;
+        }
+
+        let channels = self.channels.keys().cloned().collect::<Vec<_>>();
+        for channel in channels {
+            self.catch_up_channel(channel);
+        }
+    }
+
+    fn catch_up_channel(&mut self, channel: ChannelKey) {
+        let (oldest, keyframe, skipped) = match self.channels.get(&channel) {
+            Some(channel_media) => {
+                let (oldest, newest) =
+                    match (channel_media.queued.front(), channel_media.queued.back()) {
+                        (Some(&(_, oldest)), Some(&(_, newest))) => (oldest, newest),
+                        _ => return,
+                    };
+                if newest.wrapping_sub(oldest) as i32 as i64 <= self.limits.max_latency as i64 {
+                    return;
+                }
+
+                let keyframe = match channel_media.keyframe {
+                    Some(keyframe) => keyframe,
+                    None => return,
+                };
+                let skipped = channel_media
+                    .queued
+                    .iter()
+                    .map(|&(seq, _)| seq)
+                    .take_while(|&seq| seq < keyframe)
+                    .filter(|&seq| self.media(seq).map_or(false, |media| media.can_be_dropped))
+                    .collect::<Vec<_>>();
+                (oldest, keyframe, skipped)
+            }
+            None => return,
+        };
+        if skipped.is_empty() {
+            return;
+        }
+
+        let keyframe_timestamp = match self.media(keyframe) {
+            Some(media) => media.timestamp,
+            None => return,
+        };
+        for seq in &skipped {
+            self.remove(*seq);
+        }
+
+        self.counts.entry(channel.clone()).or_default().dropped += skipped.len() as u64;
+        self.waiting_keyframe.remove(&channel);
+        self.skips
+            .push((channel, keyframe_timestamp.wrapping_sub(oldest)));
+    }
+
+    /// Drops the queued video of the channel following a dropped frame, up to its next keyframe
+    fn drop_until_keyframe(&mut self, channel: &ChannelKey, seq: u64) {
+        let mut dependent = Vec::new();
+        let mut has_keyframe = false;
+        if let Some(channel_media) = self.channels.get(channel) {
+            for &(queued, _) in channel_media
+                .queued
+                .iter()
+                .filter(|&&(queued, _)| queued > seq)
+            {
+                let media = match self.media(queued) {
+                    Some(media) => media,
+                    None => continue,
+                };
+                match media.media_type {
+                    MediaType::Video if media.is_keyframe() => {
+                        has_keyframe = true;
+                        break;
+                    }
+                    MediaType::Video if media.can_be_dropped => dependent.push(queued),
+                    _ => (),
+                }
+            }
+        }
+
+        for queued in dependent {
+            if let Some(RtmpInput::Media(key, dropped)) = self.remove(queued) {
+                self.record_drop(&key, &dropped);
+            }
+        }
+        if has_keyframe {
+            self.waiting_keyframe.remove(channel);
+        }
+    }
+}
+
+/// Hands the content received by the server over to the streaming thread of the element
+#[derive(Default)]
+pub struct MediaQueue {
+    state: Mutex<QueueState>,
+    cond: Condvar,
+}
+
+impl MediaQueue {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn push(&self, input: RtmpInput) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+
+        if let RtmpInput::Media(ref channel, ref media) = input {
+            if state.is_full(channel, media) {
+                match state.limits.leaky {
+                    Leaky::Upstream if media.can_be_dropped => {
+                        state.record_drop(channel, media);
+                        return;
+                    }
+                    // Sequence headers are queued anyway
+                    Leaky::Upstream => (),
+                    Leaky::Downstream => state.drop_oldest(channel, media),
+                }
+            }
+
+            if state.is_waiting_keyframe(channel, media) {
+                state.record_drop(channel, media);
+                return;
+            }
+
+            state.counts.entry(channel.clone()).or_default().processed += 1;
+        }
+
+        state.push_back(input);
+        self.cond.notify_one();
+    }
+
+    /// Blocks until there is content available, the queue is set to flushing or closed, or the
+    /// deadline is reached
+    pub fn pop(&self, deadline: Option<Instant>) -> Result<RtmpInput, QueueError> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.flushing {
+                return Err(QueueError::Flushing);
+            }
+
+            state.catch_up();
+            if let Some(input) = state.pop_front() {
+                return Ok(input);
+            }
+
+            if state.closed {
+                return Err(QueueError::Closed);
+            }
+
+            state = match deadline {
+                None => self.cond.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(QueueError::Timeout);
+                    }
+                    self.cond.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    pub fn set_flushing(&self, flushing: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.flushing = flushing;
+        self.cond.notify_all();
+    }
+
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.cond.notify_all();
+    }
+
+    /// Drops any pending content and opens the queue again, with the new limits
+    pub fn reset(&self, limits: QueueLimits) {
+        let mut state = self.state.lock().unwrap();
+        state.items.clear();
+        state.front_seq = 0;
+        state.channels.clear();
+        state.closed = false;
+        state.limits = limits;
+        state.buffers = 0;
+        state.bytes = 0;
+        state.waiting_keyframe.clear();
+        state.counts.clear();
+        state.skips.clear();
+    }
+
+    /// Counts of the channels which had media dropped since the last call
+    pub fn take_drops(&self) -> Vec<(ChannelKey, DropCounts)> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .counts
+            .iter_mut()
+            .filter(|(_, counts)| counts.pending > 0)
+            .map(|(channel, counts)| {
+                let taken = *counts;
+                counts.pending = 0;
+                (channel.clone(), taken)
+            })
+            .collect()
+    }
+
+    /// Channels which skipped content to catch up since the last call, with the skipped
+    /// milliseconds
+    pub fn take_skips(&self) -> Vec<(ChannelKey, u32)> {
+        let mut state = self.state.lock().unwrap();
+        mem::take(&mut state.skips)
+    }
+
+    /// Media of the channel dropped so far, because of the limits or to catch up
+    pub fn dropped(&self, channel: &ChannelKey) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.counts.get(channel).map_or(0, |counts| counts.dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn channel(stream_key: &str) -> ChannelKey {
+        ChannelKey {
+            app_name: "live".to_string(),
+            stream_key: stream_key.to_string(),
+        }
+    }
+
+    fn video(timestamp: u32, is_keyframe: bool) -> Media {
+        let frame_type = if is_keyframe { 0x17 } else { 0x27 };
+        Media {
+            media_type: MediaType::Video,
+            data: Bytes::copy_from_slice(&[frame_type, 1, 0, 0, 0, 0]),
+            timestamp,
+            can_be_dropped: true,
+        }
+    }
+
+    fn sequence_header(timestamp: u32) -> Media {
+        Media {
+            media_type: MediaType::Video,
+            data: Bytes::from_static(&[0x17, 0, 0, 0, 0, 1]),
+            timestamp,
+            can_be_dropped: false,
+        }
+    }
+
+    fn audio(timestamp: u32) -> Media {
+        Media {
+            media_type: MediaType::Audio,
+            data: Bytes::from_static(&[0xaf, 1, 0]),
+            timestamp,
+            can_be_dropped: true,
+        }
+    }
+
+    fn queue(limits: QueueLimits) -> MediaQueue {
+        let queue = MediaQueue::new();
+        queue.reset(limits);
+        queue
+    }
+
+    fn push(queue: &MediaQueue, stream_key: &str, media: Media) {
+        queue.push(RtmpInput::Media(channel(stream_key), media));
+    }
+
+    /// Stream keys and timestamps of the queued media
+    fn pop_all(queue: &MediaQueue) -> Vec<(String, u32)> {
+        queue.close();
+        let mut popped = Vec::new();
+        while let Ok(input) = queue.pop(None) {
+            if let RtmpInput::Media(channel, media) = input {
+                popped.push((channel.stream_key, media.timestamp));
+            }
+        }
+        popped
+    }
+
+    fn timestamps(stream_key: &str, timestamps: &[u32]) -> Vec<(String, u32)> {
+        timestamps
+            .iter()
+            .map(|&timestamp| (stream_key.to_string(), timestamp))
+            .collect()
+    }
+
+    #[test]
+    fn drops_the_new_media_when_leaking_upstream() {
+        let queue = queue(QueueLimits {
+            max_buffers: 2,
+            leaky: Leaky::Upstream,
+            ..Default::default()
+        });
+        push(&queue, "a", video(0, true));
+        push(&queue, "a", audio(10));
+        push(&queue, "a", audio(20));
+        // Sequence headers are queued anyway
+        push(&queue, "a", sequence_header(30));
+
+        assert_eq!(queue.dropped(&channel("a")), 1);
+        assert_eq!(pop_all(&queue), timestamps("a", &[0, 10, 30]));
+    }
+
+    #[test]
+    fn drops_the_oldest_media_when_leaking_downstream() {
+        let queue = queue(QueueLimits {
+            max_buffers: 2,
+            ..Default::default()
+        });
+        push(&queue, "a", sequence_header(0));
+        push(&queue, "a", audio(10));
+        push(&queue, "a", audio(20));
+
+        assert_eq!(queue.dropped(&channel("a")), 1);
+        assert_eq!(pop_all(&queue), timestamps("a", &[0, 20]));
+    }
+
+    #[test]
+    fn drops_the_oldest_media_of_the_channel_over_time() {
+        let queue = queue(QueueLimits {
+            max_time: 100,
+            ..Default::default()
+        });
+        push(&queue, "b", audio(0));
+        push(&queue, "a", audio(0));
+        push(&queue, "a", audio(50));
+        push(&queue, "a", audio(100));
+        push(&queue, "a", audio(150));
+
+        let mut expected = timestamps("b", &[0]);
+        expected.extend(timestamps("a", &[50, 100, 150]));
+        assert_eq!(pop_all(&queue), expected);
+    }
+
+    #[test]
+    fn drops_the_video_depending_on_a_dropped_frame() {
+        let queue = queue(QueueLimits {
+            max_buffers: 5,
+            ..Default::default()
+        });
+        push(&queue, "a", video(0, true));
+        push(&queue, "a", video(40, false));
+        push(&queue, "a", audio(50));
+        push(&queue, "a", video(80, false));
+        push(&queue, "a", video(120, true));
+        push(&queue, "a", audio(130));
+        push(&queue, "a", video(160, false));
+
+        assert_eq!(queue.dropped(&channel("a")), 3);
+        assert_eq!(pop_all(&queue), timestamps("a", &[50, 120, 130, 160]));
+    }
+
+    #[test]
+    fn skips_to_the_latest_keyframe() {
+        let queue = queue(QueueLimits {
+            max_latency: 100,
+            ..Default::default()
+        });
+        push(&queue, "a", sequence_header(0));
+        push(&queue, "a", video(0, true));
+        push(&queue, "a", audio(20));
+        push(&queue, "a", video(40, false));
+        push(&queue, "a", video(80, true));
+        push(&queue, "a", video(120, false));
+        push(&queue, "a", video(160, false));
+
+        assert_eq!(pop_all(&queue), timestamps("a", &[0, 80, 120, 160]));
+        assert_eq!(queue.dropped(&channel("a")), 3);
+        assert_eq!(queue.take_skips(), vec![(channel("a"), 80)]);
+    }
+
+    #[test]
+    fn skips_the_lagging_channel_behind_the_front() {
+        let queue = queue(QueueLimits {
+            max_latency: 100,
+            ..Default::default()
+        });
+        push(&queue, "a", audio(0));
+        push(&queue, "b", video(0, true));
+        push(&queue, "b", video(40, false));
+        push(&queue, "a", audio(50));
+        push(&queue, "b", video(200, true));
+        push(&queue, "b", video(240, false));
+
+        let mut expected = timestamps("a", &[0, 50]);
+        expected.extend(timestamps("b", &[200, 240]));
+        assert_eq!(pop_all(&queue), expected);
+        assert_eq!(queue.take_skips(), vec![(channel("b"), 200)]);
+    }
+
+    #[test]
+    fn drops_the_video_until_the_next_keyframe_is_pushed() {
+        let queue = queue(QueueLimits {
+            max_buffers: 3,
+            ..Default::default()
+        });
+        push(&queue, "a", video(0, true));
+        push(&queue, "a", video(40, false));
+        push(&queue, "a", video(80, false));
+        push(&queue, "a", video(120, false));
+        push(&queue, "a", audio(130));
+        push(&queue, "a", video(160, true));
+        push(&queue, "a", video(200, false));
+
+        assert_eq!(queue.dropped(&channel("a")), 4);
+        assert_eq!(pop_all(&queue), timestamps("a", &[130, 160, 200]));
+    }
+}
@@ -0,0 +1,489 @@
+use crate::connection::{Connection, ConnectionError, ReadResult};
+use crate::data::{ChannelKey, RtmpInput};
+use crate::server::{Server, ServerResult, Subscription, Subscriptions};
+use crate::stats::{ServerStats, StatsHandle};
+use gst::prelude::*;
+use gst::{gst_debug, gst_error, gst_info, gst_warning};
+use once_cell::sync::Lazy;
+use slab::Slab;
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::{io, thread};
+
+/// Type of the context used to share a server between elements, it carries the "address" field
+pub const CONTEXT_TYPE: &str = "gst.rtmpsrv.server";
+
+const IDLE_INTERVAL: Duration = Duration::from_millis(1);
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "rtmpsrvserver",
+        gst::DebugColorFlags::empty(),
+        Some("RTMP Server"),
+    )
+});
+
+/// Servers currently running in the process, by the address they listen on
+static SERVERS: Lazy<Mutex<HashMap<String, Registration>>> = Lazy::new(Default::default);
+
+/// A running server and the number of elements using it
+struct Registration {
+    server: Arc<SharedServer>,
+    users: usize,
+}
+
+/// Requests of the elements of the pipeline, handled by the connections thread
+enum LocalRequest {
+    Publish(RtmpInput),
+    DisconnectPublisher(ChannelKey),
+}
+
+/// A listener and its connection threads, shared by all the elements using the same address
+pub struct SharedServer {
+    address: String,
+    /// Accepting RTMPS connections rather than RTMP ones
+    is_tls: bool,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    stats: Arc<Mutex<ServerStats>>,
+    shutdown: Arc<AtomicBool>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    /// Content published by the elements of the pipeline, and their other requests
+    local_requests: Mutex<Sender<LocalRequest>>,
+}
+
+/// Joins the server shared through the pipeline context when it listens on the same address, or
+/// starts one on the address and shares it with the other elements of the pipeline
+pub fn acquire_shared(
+    element: &gst::Element,
+    context: &Mutex<Option<gst::Context>>,
+    address: &str,
+    tls: Option<Arc<rustls::ServerConfig>>,
+) -> io::Result<Arc<SharedServer>> {
+    if context.lock().unwrap().is_none() {
+        // The bin or the application answers by calling set_context() synchronously
+        let _ = element.post_message(
+            gst::message::NeedContext::builder(CONTEXT_TYPE)
+                .src(Some(element))
+                .build(),
+        );
+    }
+
+    let shared_address = context.lock().unwrap().as_ref().and_then(context_address);
+    match shared_address {
+        Some(ref shared_address) if shared_address == address => {
+            return SharedServer::acquire(shared_address, tls);
+        }
+        // Another element of the pipeline listens elsewhere, the context stays pointing to it
+        Some(_) => return SharedServer::acquire(address, tls),
+        None => (),
+    }
+
+    let server = SharedServer::acquire(address, tls)?;
+    let context = new_context(server.address());
+    element.set_context(&context);
+    let _ = element.post_message(
+        gst::message::HaveContext::builder(context)
+            .src(Some(element))
+            .build(),
+    );
+    Ok(server)
+}
+
+/// Creates the context pointing the other elements of the pipeline to the server
+fn new_context(address: &str) -> gst::Context {
+    let mut context = gst::Context::new(CONTEXT_TYPE, true);
+    {
+        let context = context.get_mut().unwrap();
+        let structure = context.get_mut_structure();
+        structure.set("address", &address);
+    }
+    context
+}
+
+/// Address of the server the context points to
+fn context_address(context: &gst::Context) -> Option<String> {
+    context
+        .get_structure()
+        .get::<String>("address")
+        .ok()
+        .flatten()
+}
+
+impl SharedServer {
+    /// Returns the server listening on the address, starting it if there is none yet. The server
+    /// only accepts RTMPS connections when started with a TLS configuration, joining a running
+    /// server fails when it does not match.
+    pub fn acquire(
+        address: &str,
+        tls: Option<Arc<rustls::ServerConfig>>,
+    ) -> io::Result<Arc<SharedServer>> {
+        let mut servers = SERVERS.lock().unwrap();
+        if let Some(registration) = servers.get_mut(address) {
+            if registration.server.is_stopped() {
+                // Its connections thread is gone, the elements using it move to a new one
+                gst_info!(CAT, "Restarting the server on {}", address);
+                registration.server.stop();
+                registration.server = Arc::new(SharedServer::start(address, tls)?);
+                registration.users += 1;
+                return Ok(registration.server.clone());
+            }
+
+            if registration.server.is_tls != tls.is_some() {
+                let protocol = if registration.server.is_tls {
+                    "RTMPS"
+                } else {
+                    "RTMP"
+                };
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is already serving {}", address, protocol),
+                ));
+            }
+            registration.users += 1;
+            return Ok(registration.server.clone());
+        }
+
+        let server = Arc::new(SharedServer::start(address, tls)?);
+        servers.insert(
+            address.to_string(),
+            Registration {
+                server: server.clone(),
+                users: 1,
+            },
+        );
+        Ok(server)
+    }
+
+    /// Gives the server back, it is stopped once no element is using it anymore
+    pub fn release(server: Arc<SharedServer>) {
+        // Holding the registry lock so the address is not bound again before the listener is gone
+        let mut servers = SERVERS.lock().unwrap();
+        let address = server.address.clone();
+        drop(server);
+
+        let is_unused = match servers.get_mut(&address) {
+            Some(registration) => {
+                registration.users -= 1;
+                registration.users == 0
+            }
+            None => false,
+        };
+        if is_unused {
+            if let Some(registration) = servers.remove(&address) {
+                registration.server.stop();
+            }
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn subscribe(&self, subscription: Subscription) -> u64 {
+        self.subscriptions.lock().unwrap().insert(subscription)
+    }
+
+    pub fn unsubscribe(&self, id: u64) {
+        self.subscriptions.lock().unwrap().remove(id);
+    }
+
+    /// Reads the counters of the server, it does not count as a user of the server
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle::new(&self.address, self.stats.clone())
+    }
+
+    /// Publishes content to the clients watching the channel
+    pub fn publish(&self, input: RtmpInput) {
+        // The connections thread is only gone once the server stopped
+        let _ = self
+            .local_requests
+            .lock()
+            .unwrap()
+            .send(LocalRequest::Publish(input));
+    }
+
+    /// Closes the connection of the client publishing to the channel
+    pub fn disconnect_publisher(&self, channel_key: ChannelKey) {
+        let _ = self
+            .local_requests
+            .lock()
+            .unwrap()
+            .send(LocalRequest::DisconnectPublisher(channel_key));
+    }
+
+    fn start(address: &str, tls: Option<Arc<rustls::ServerConfig>>) -> io::Result<SharedServer> {
+        let listener = TcpListener::bind(address)?;
+        // Polled, so the accept thread can notice the shutdown
+        listener.set_nonblocking(true)?;
+
+        let is_tls = tls.is_some();
+        let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
+        let stats = Arc::new(Mutex::new(ServerStats::default()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let (connection_sender, connection_receiver) = channel();
+        let (local_sender, local_receiver) = channel();
+
+        let handler_subscriptions = subscriptions.clone();
+        let handler_stats = stats.clone();
+        let handler_shutdown = shutdown.clone();
+        let handler = thread::spawn(move || {
+            handle_connections(
+                handler_subscriptions,
+                handler_stats,
+                connection_receiver,
+                local_receiver,
+                tls,
+                handler_shutdown,
+            )
+        });
+
+        let accept_shutdown = shutdown.clone();
+        let acceptor =
+            thread::spawn(move || accept_connections(connection_sender, listener, accept_shutdown));
+
+        Ok(SharedServer {
+            address: address.to_string(),
+            is_tls,
+            subscriptions,
+            stats,
+            shutdown,
+            threads: Mutex::new(vec![acceptor, handler]),
+            local_requests: Mutex::new(local_sender),
+        })
+    }
+
+    /// Whether the server was stopped, or its connections thread is gone
+    fn is_stopped(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        for handle in self.threads.lock().unwrap().drain(..) {
+            if handle.join().is_err() {
+                gst_error!(CAT, "Server thread panicked");
+            }
+        }
+    }
+}
+
+/// Shuts the whole server down when the connections thread ends, even by panicking, so that the
+/// address gets released and the elements restart it
+struct ShutdownGuard(Arc<AtomicBool>);
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Accepts TCP connections until the server is shut down
+fn accept_connections(
+    connection_sender: Sender<(TcpStream, SocketAddr)>,
+    listener: TcpListener,
+    shutdown: Arc<AtomicBool>,
+) {
+    gst_info!(CAT, "Listening for connections");
+    while !shutdown.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, peer_address)) => {
+                gst_debug!(CAT, "New connection from {}", peer_address);
+                if let Err(error) = stream.set_nonblocking(false) {
+                    gst_warning!(CAT, "Failed to configure connection: {:?}", error);
+                    continue;
+                }
+
+                if connection_sender.send((stream, peer_address)).is_err() {
+                    // The connections thread is gone, nothing else to do
+                    return;
+                }
+            }
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_INTERVAL);
+            }
+            Err(error) => gst_warning!(CAT, "Error accepting connection: {:?}", error),
+        }
+    }
+}
+
+/// Handle the lifecycle of all TCP connections by sending and receiving data
+fn handle_connections(
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    stats: Arc<Mutex<ServerStats>>,
+    connection_receiver: Receiver<(TcpStream, SocketAddr)>,
+    local_receiver: Receiver<LocalRequest>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    shutdown: Arc<AtomicBool>,
+) {
+    let mut connections = Slab::new();
+    let mut connection_ids = HashSet::new();
+    let mut server = Server::new(subscriptions.clone(), stats.clone());
+    // Dropped before the server, which closes the queues of the elements
+    let _guard = ShutdownGuard(shutdown.clone());
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let mut is_idle = true;
+
+        match connection_receiver.try_recv() {
+            Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => (),
+            Ok((stream, peer_address)) => {
+                is_idle = false;
+
+                let connection = Connection::new(stream, tls.as_ref());
+                let id = connections.insert(connection);
+                let connection = connections.get_mut(id).unwrap();
+                connection.connection_id = Some(id);
+                connection_ids.insert(id);
+
+                gst_debug!(CAT, "Connection {} started", id);
+                server.notify_connection_opened(id, &peer_address.to_string());
+            }
+        }
+
+        let mut ids_to_clear = Vec::new();
+        let mut packets_to_write = Vec::new();
+        while let Ok(request) = local_receiver.try_recv() {
+            is_idle = false;
+            let results = match request {
+                LocalRequest::Publish(input) => server.local_input(input),
+                LocalRequest::DisconnectPublisher(channel_key) => {
+                    server.disconnect_publisher(&channel_key)
+                }
+            };
+            for result in results {
+                match result {
+                    ServerResult::OutboundPacket {
+                        target_connection_id,
+                        packet,
+                    } => packets_to_write.push((target_connection_id, packet)),
+                    ServerResult::DisconnectConnection { connection_id } => {
+                        ids_to_clear.push(connection_id)
+                    }
+                }
+            }
+        }
+
+        let handshake_timeout = subscriptions.lock().unwrap().handshake_timeout();
+        for connection_id in &connection_ids {
+            let connection = connections.get_mut(*connection_id).unwrap();
+            let is_timed_out = match (connection.handshake_duration(), handshake_timeout) {
+                (Some(duration), Some(timeout)) => duration > timeout,
+                _ => false,
+            };
+            if is_timed_out {
+                gst_info!(CAT, "Handshake of connection {} timed out", connection_id);
+                server.notify_handshake_timed_out(*connection_id);
+                ids_to_clear.push(*connection_id);
+                continue;
+            }
+
+            match connection.read() {
+                Err(ConnectionError::SocketClosed) => {
+                    gst_debug!(CAT, "Socket closed for id {}", connection_id);
+                    ids_to_clear.push(*connection_id);
+                }
+
+                Err(error) => {
+                    gst_warning!(
+                        CAT,
+                        "I/O error while reading connection {}: {:?}",
+                        connection_id,
+                        error
+                    );
+                    ids_to_clear.push(*connection_id);
+                }
+
+                Ok(result) => match result {
+                    ReadResult::NoBytesReceived => (),
+                    ReadResult::HandshakingInProgress => is_idle = false,
+                    ReadResult::BytesReceived { buffer, byte_count } => {
+                        is_idle = false;
+                        stats
+                            .lock()
+                            .unwrap()
+                            .bytes_received(*connection_id, byte_count);
+                        let mut server_results =
+                            match server.bytes_received(*connection_id, &buffer[..byte_count]) {
+                                Ok(results) => results,
+                                Err(error) => {
+                                    gst_warning!(
+                                        CAT,
+                                        "Input caused the following server error: {}",
+                                        error
+                                    );
+                                    ids_to_clear.push(*connection_id);
+                                    continue;
+                                }
+                            };
+
+                        for result in server_results.drain(..) {
+                            match result {
+                                ServerResult::OutboundPacket {
+                                    target_connection_id,
+                                    packet,
+                                } => {
+                                    packets_to_write.push((target_connection_id, packet));
+                                }
+
+                                ServerResult::DisconnectConnection {
+                                    connection_id: id_to_close,
+                                } => {
+                                    ids_to_clear.push(id_to_close);
+                                }
+                            }
+                        }
+                    }
+                },
+            }
+        }
+
+        for (connection_id, packet) in packets_to_write.drain(..) {
+            let connection = connections.get_mut(connection_id).unwrap();
+            stats
+                .lock()
+                .unwrap()
+                .bytes_sent(connection_id, packet.bytes.len());
+            connection.write(packet.bytes);
+        }
+
+        for closed_id in ids_to_clear {
+            // The same connection can be asked to be closed more than once
+            if !connection_ids.remove(&closed_id) {
+                continue;
+            }
+
+            gst_debug!(CAT, "Connection {} closed", closed_id);
+            connections.remove(closed_id).close();
+            server.notify_connection_closed(closed_id);
+        }
+
+        // Avoid spinning while there is nothing to be done
+        if is_idle {
+            thread::sleep(IDLE_INTERVAL);
+        }
+    }
+
+    for result in server.shutdown() {
+        if let ServerResult::OutboundPacket {
+            target_connection_id,
+            packet,
+        } = result
+        {
+            if let Some(connection) = connections.get(target_connection_id) {
+                connection.write(packet.bytes);
+            }
+        }
+    }
+
+    for connection in connections.drain() {
+        connection.close();
+    }
+    gst_debug!(CAT, "Connections handler stopped");
+}
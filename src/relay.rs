@@ -0,0 +1,662 @@
+use crate::data::{ChannelKey, MediaType, PushStatus, RtmpInput};
+use crate::flv::{AudioPacket, AudioPacketType, VideoPacket, VideoPacketType};
+use crate::queue::MediaQueue;
+use bytes::Bytes;
+use rml_rtmp::handshake::{Handshake, HandshakeProcessResult, PeerType};
+use rml_rtmp::sessions::{
+    ClientSession, ClientSessionConfig, ClientSessionEvent, ClientSessionResult,
+    PublishRequestType, StreamMetadata,
+};
+use rml_rtmp::time::RtmpTimestamp;
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{
+    sync_channel, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError,
+};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+const DEFAULT_PORT: u16 = 1935;
+const BUFFER_SIZE: usize = 4096;
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+/// A target not reading for that long is reconnected
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Content waiting to be forwarded, beyond which it is dropped rather than holding the server back
+const QUEUE_SIZE: usize = 512;
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// An upstream server the content published to some stream keys is forwarded to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushTarget {
+    /// Stream key whose content is forwarded, any key when not set
+    pub stream_key: Option<String>,
+    pub url: String,
+    host: String,
+    port: u16,
+    app_name: String,
+    target_stream_key: String,
+}
+
+impl PushTarget {
+    /// Parses a "<stream key>=rtmp://host[:port]/app/key" entry, where the stream key can be "*"
+    pub fn parse(entry: &str) -> Result<PushTarget, String> {
+        let (stream_key, url) = match entry.find('=') {
+            Some(index) if !entry[..index].contains("://") => {
+                (&entry[..index], &entry[index + 1..])
+            }
+            _ => ("*", entry),
+        };
+
+        let location = url
+            .strip_prefix("rtmp://")
+            .ok_or_else(|| format!("Unsupported push target url: {}", url))?;
+        let (authority, path) = location.split_at(
+            location
+                .find('/')
+                .ok_or_else(|| format!("Missing application in {}", url))?,
+        );
+        let (app_name, target_stream_key) = match path[1..].rfind('/') {
+            Some(index) => (&path[1..index + 1], &path[index + 2..]),
+            None => return Err(format!("Missing stream key in {}", url)),
+        };
+        if app_name.is_empty() || target_stream_key.is_empty() {
+            return Err(format!("Missing application or stream key in {}", url));
+        }
+
+        let (host, port) = match authority.rfind(':') {
+            Some(index) => {
+                let port = authority[index + 1..]
+                    .parse()
+                    .map_err(|_| format!("Invalid port in {}", url))?;
+                (&authority[..index], port)
+            }
+            None => (authority, DEFAULT_PORT),
+        };
+
+        Ok(PushTarget {
+            stream_key: match stream_key {
+                "*" | "" => None,
+                key => Some(key.to_string()),
+            },
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            app_name: app_name.to_string(),
+            target_stream_key: target_stream_key.to_string(),
+        })
+    }
+
+    pub fn matches(&self, stream_key: &str) -> bool {
+        self.stream_key
+            .as_ref()
+            .map_or(true, |key| key == stream_key)
+    }
+}
+
+impl fmt::Display for PushTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.stream_key {
+            Some(ref key) => write!(f, "{}={}", key, self.url),
+            None => write!(f, "*={}", self.url),
+        }
+    }
+}
+
+enum RelayInput {
+    Metadata(StreamMetadata),
+    Media {
+        media_type: MediaType,
+        data: Bytes,
+        timestamp: u32,
+        is_sequence_header: bool,
+    },
+    /// Content was dropped since the previous input, the headers dropped with it follow and the
+    /// video has to wait for a keyframe
+    Resync(Vec<RelayInput>),
+}
+
+impl RelayInput {
+    fn is_header(&self) -> bool {
+        match self {
+            RelayInput::Metadata(_) => true,
+            RelayInput::Media {
+                is_sequence_header, ..
+            } => *is_sequence_header,
+            RelayInput::Resync(_) => false,
+        }
+    }
+}
+
+/// Forwards the content of a channel to one push target, from its own thread. The thread stops
+/// once the relay is dropped.
+pub struct Relay {
+    sender: SyncSender<RelayInput>,
+    /// Headers dropped since the queue got full, none while nothing is dropped
+    dropped_headers: RefCell<Option<Vec<RelayInput>>>,
+}
+
+impl Relay {
+    pub fn start(
+        target: PushTarget,
+        channel_key: ChannelKey,
+        status_sink: Arc<MediaQueue>,
+    ) -> Relay {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        thread::spawn(move || {
+            RelayWorker {
+                target,
+                channel: channel_key,
+                status_sink,
+                inputs: receiver,
+                is_publishing: false,
+                metadata: None,
+                video_sequence_header: None,
+                audio_sequence_header: None,
+                audio_channel_config: None,
+                is_waiting_keyframe: true,
+            }
+            .run()
+        });
+
+        Relay {
+            sender,
+            dropped_headers: RefCell::new(None),
+        }
+    }
+
+    pub fn send_metadata(&self, metadata: StreamMetadata) {
+        self.send(RelayInput::Metadata(metadata));
+    }
+
+    pub fn send_media(
+        &self,
+        media_type: MediaType,
+        data: Bytes,
+        timestamp: u32,
+        is_sequence_header: bool,
+    ) {
+        self.send(RelayInput::Media {
+            media_type,
+            data,
+            timestamp,
+            is_sequence_header,
+        });
+    }
+
+    /// Queues the input unless the worker is falling behind, the server thread never waits for it
+    fn send(&self, input: RelayInput) {
+        let mut dropped_headers = self.dropped_headers.borrow_mut();
+        if let Some(headers) = dropped_headers.take() {
+            match self.sender.try_send(RelayInput::Resync(headers)) {
+                Ok(()) => (),
+                Err(TrySendError::Full(RelayInput::Resync(mut headers))) => {
+                    if input.is_header() {
+                        headers.push(input);
+                    }
+                    *dropped_headers = Some(headers);
+                    return;
+                }
+                // The worker stops once the relay is dropped
+                Err(_) => return,
+            }
+        }
+
+        if let Err(TrySendError::Full(input)) = self.sender.try_send(input) {
+            let mut headers = Vec::new();
+            if input.is_header() {
+                headers.push(input);
+            }
+            *dropped_headers = Some(headers);
+        }
+    }
+}
+
+enum RelayError {
+    /// The relay was dropped, the worker has to stop
+    Stopped,
+    Failed(String),
+}
+
+impl From<io::Error> for RelayError {
+    fn from(error: io::Error) -> Self {
+        RelayError::Failed(error.to_string())
+    }
+}
+
+struct RelayWorker {
+    target: PushTarget,
+    channel: ChannelKey,
+    status_sink: Arc<MediaQueue>,
+    inputs: Receiver<RelayInput>,
+    is_publishing: bool,
+    // Replayed after reconnecting, so the upstream server can decode the stream again
+    metadata: Option<StreamMetadata>,
+    video_sequence_header: Option<Bytes>,
+    audio_sequence_header: Option<Bytes>,
+    audio_channel_config: Option<Bytes>,
+    /// The video following a (re)connection or dropped content starts with a keyframe
+    is_waiting_keyframe: bool,
+}
+
+impl RelayWorker {
+    fn run(mut self) {
+        let mut reconnect_delay = MIN_RECONNECT_DELAY;
+
+        loop {
+            self.report(PushStatus::Connecting);
+            let reason = match self.publish() {
+                Err(RelayError::Stopped) => return,
+                Err(RelayError::Failed(error)) => error,
+                Ok(()) => "Connection closed by the server".to_string(),
+            };
+            println!("Push to {} stopped: {}", self.target.url, reason);
+            self.report(PushStatus::Disconnected(reason));
+
+            // Only back off further while the target keeps failing
+            if self.is_publishing {
+                reconnect_delay = MIN_RECONNECT_DELAY;
+            }
+
+            // Keep track of the headers while waiting, the content itself is dropped
+            let retry_at = Instant::now() + reconnect_delay;
+            loop {
+                let now = Instant::now();
+                if now >= retry_at {
+                    break;
+                }
+                match self.inputs.recv_timeout(retry_at - now) {
+                    Ok(input) => self.remember(&input),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    fn report(&self, status: PushStatus) {
+        self.status_sink.push(RtmpInput::PushStatus(
+            self.channel.clone(),
+            self.target.url.clone(),
+            status,
+        ));
+    }
+
+    fn remember(&mut self, input: &RelayInput) {
+        match input {
+            RelayInput::Metadata(metadata) => self.metadata = Some(metadata.clone()),
+            RelayInput::Media {
+                media_type,
+                data,
+                is_sequence_header: true,
+                ..
+            } => match media_type {
+                MediaType::Video => self.video_sequence_header = Some(data.clone()),
+                MediaType::Audio => match AudioPacket::parse(data) {
+                    Some(ref packet)
+                        if packet.packet_type == AudioPacketType::MultichannelConfig =>
+                    {
+                        self.audio_channel_config = Some(data.clone())
+                    }
+                    _ => {
+                        self.audio_sequence_header = Some(data.clone());
+                        self.audio_channel_config = None;
+                    }
+                },
+            },
+            RelayInput::Media { .. } => (),
+            RelayInput::Resync(headers) => {
+                for header in headers {
+                    self.remember(header);
+                }
+            }
+        }
+    }
+
+    /// Whether the input is forwarded, the video waiting for a keyframe after a gap
+    fn is_forwarded(&mut self, input: &RelayInput) -> bool {
+        match input {
+            RelayInput::Media {
+                media_type: MediaType::Video,
+                data,
+                is_sequence_header: false,
+                ..
+            } if self.is_waiting_keyframe => {
+                self.is_waiting_keyframe = !is_keyframe(data);
+                !self.is_waiting_keyframe
+            }
+            _ => true,
+        }
+    }
+
+    /// Connects to the push target and forwards the content until the server closes the connection
+    fn publish(&mut self) -> Result<(), RelayError> {
+        self.is_publishing = false;
+
+        let address = format!("{}:{}", self.target.host, self.target.port);
+        let mut socket = TcpStream::connect(&address)?;
+        socket.set_read_timeout(Some(READ_TIMEOUT))?;
+        socket.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        socket.set_nodelay(true)?;
+
+        let remaining_bytes = self.handshake(&mut socket)?;
+
+        let (mut session, results) =
+            ClientSession::new(ClientSessionConfig::new()).map_err(session_error)?;
+        self.handle_results(&mut socket, &mut session, results)?;
+
+        let result = session
+            .request_connection(self.target.app_name.clone())
+            .map_err(session_error)?;
+        self.handle_results(&mut socket, &mut session, vec![result])?;
+        let results = session
+            .handle_input(&remaining_bytes)
+            .map_err(session_error)?;
+        self.handle_results(&mut socket, &mut session, results)?;
+
+        let mut buffer = [0; BUFFER_SIZE];
+        loop {
+            loop {
+                let input = match self.inputs.try_recv() {
+                    Ok(input) => input,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        if let Ok(results) = session.stop_publishing() {
+                            let _ = self.handle_results(&mut socket, &mut session, results);
+                        }
+                        return Err(RelayError::Stopped);
+                    }
+                };
+
+                self.remember(&input);
+                if self.is_publishing && self.is_forwarded(&input) {
+                    let result = self.forward(&mut session, input)?;
+                    socket.write_all(&result)?;
+                }
+            }
+
+            let read_count = match socket.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(read_count) => read_count,
+                Err(ref error) if is_timeout(error) => continue,
+                Err(error) => return Err(error.into()),
+            };
+
+            let results = session
+                .handle_input(&buffer[..read_count])
+                .map_err(session_error)?;
+            self.handle_results(&mut socket, &mut session, results)?;
+        }
+    }
+
+    fn handshake(&self, socket: &mut TcpStream) -> Result<Vec<u8>, RelayError> {
+        let mut handshake = Handshake::new(PeerType::Client);
+        let p0_and_p1 = handshake
+            .generate_outbound_p0_and_p1()
+            .map_err(|error| RelayError::Failed(format!("{:?}", error)))?;
+        socket.write_all(&p0_and_p1)?;
+
+        let mut buffer = [0; BUFFER_SIZE];
+        loop {
+            let read_count = match socket.read(&mut buffer) {
+                Ok(0) => return Err(RelayError::Failed("Connection closed".to_string())),
+                Ok(read_count) => read_count,
+                Err(ref error) if is_timeout(error) => continue,
+                Err(error) => return Err(error.into()),
+            };
+
+            match handshake.process_bytes(&buffer[..read_count]) {
+                Err(error) => return Err(RelayError::Failed(format!("{:?}", error))),
+                Ok(HandshakeProcessResult::InProgress { response_bytes }) => {
+                    socket.write_all(&response_bytes)?;
+                }
+                Ok(HandshakeProcessResult::Completed {
+                    response_bytes,
+                    remaining_bytes,
+                }) => {
+                    socket.write_all(&response_bytes)?;
+                    return Ok(remaining_bytes);
+                }
+            }
+        }
+    }
+
+    fn handle_results(
+        &mut self,
+        socket: &mut TcpStream,
+        session: &mut ClientSession,
+        results: Vec<ClientSessionResult>,
+    ) -> Result<(), RelayError> {
+        for result in results {
+            match result {
+                ClientSessionResult::OutboundResponse(packet) => socket.write_all(&packet.bytes)?,
+
+                ClientSessionResult::RaisedEvent(ClientSessionEvent::ConnectionRequestAccepted) => {
+                    let result = session
+                        .request_publishing(
+                            self.target.target_stream_key.clone(),
+                            PublishRequestType::Live,
+                        )
+                        .map_err(session_error)?;
+                    self.handle_results(socket, session, vec![result])?;
+                }
+
+                ClientSessionResult::RaisedEvent(ClientSessionEvent::PublishRequestAccepted) => {
+                    println!("Publishing {} to {}", self.channel, self.target.url);
+                    self.is_publishing = true;
+                    self.is_waiting_keyframe = true;
+                    self.report(PushStatus::Publishing);
+
+                    let mut replay = Vec::new();
+                    if let Some(ref metadata) = self.metadata {
+                        replay.push(RelayInput::Metadata(metadata.clone()));
+                    }
+                    if let Some(ref data) = self.video_sequence_header {
+                        replay.push(sequence_header(MediaType::Video, data.clone()));
+                    }
+                    if let Some(ref data) = self.audio_sequence_header {
+                        replay.push(sequence_header(MediaType::Audio, data.clone()));
+                    }
+                    if let Some(ref data) = self.audio_channel_config {
+                        replay.push(sequence_header(MediaType::Audio, data.clone()));
+                    }
+                    for input in replay {
+                        let bytes = self.forward(session, input)?;
+                        socket.write_all(&bytes)?;
+                    }
+                }
+
+                ClientSessionResult::RaisedEvent(
+                    ClientSessionEvent::ConnectionRequestRejected { description },
+                ) => {
+                    return Err(RelayError::Failed(format!(
+                        "Connection rejected: {}",
+                        description
+                    )))
+                }
+
+                x => println!("Push target {} result: {:?}", self.target.url, x),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn forward(
+        &mut self,
+        session: &mut ClientSession,
+        input: RelayInput,
+    ) -> Result<Vec<u8>, RelayError> {
+        let result = match input {
+            RelayInput::Resync(headers) => {
+                self.is_waiting_keyframe = true;
+                let mut bytes = Vec::new();
+                for header in headers {
+                    bytes.extend(self.forward(session, header)?);
+                }
+                return Ok(bytes);
+            }
+            RelayInput::Metadata(metadata) => session.publish_metadata(&metadata),
+            RelayInput::Media {
+                media_type: MediaType::Video,
+                data,
+                timestamp,
+                is_sequence_header,
+            } => {
+                session.publish_video_data(data, RtmpTimestamp::new(timestamp), !is_sequence_header)
+            }
+            RelayInput::Media {
+                media_type: MediaType::Audio,
+                data,
+                timestamp,
+                is_sequence_header,
+            } => {
+                session.publish_audio_data(data, RtmpTimestamp::new(timestamp), !is_sequence_header)
+            }
+        };
+
+        match result.map_err(session_error)? {
+            ClientSessionResult::OutboundResponse(packet) => Ok(packet.bytes),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+fn sequence_header(media_type: MediaType, data: Bytes) -> RelayInput {
+    RelayInput::Media {
+        media_type,
+        data,
+        timestamp: 0,
+        is_sequence_header: true,
+    }
+}
+
+fn is_keyframe(data: &Bytes) -> bool {
+    match VideoPacket::parse(data) {
+        Some(packet) => packet.packet_type == VideoPacketType::CodedFrames && packet.is_keyframe,
+        None => false,
+    }
+}
+
+/// Reads time out regularly so the content to forward is not held back
+fn is_timeout(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::WouldBlock || error.kind() == io::ErrorKind::TimedOut
+}
+
+fn session_error<E: fmt::Debug>(error: E) -> RelayError {
+    RelayError::Failed(format!("{:?}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn media(is_sequence_header: bool) -> RelayInput {
+        RelayInput::Media {
+            media_type: MediaType::Video,
+            data: Bytes::from_static(&[0x27, 0x01, 0, 0, 0]),
+            timestamp: 0,
+            is_sequence_header,
+        }
+    }
+
+    #[test]
+    fn parses_push_target() {
+        let target = PushTarget::parse("somekey=rtmp://example.com/live/target").unwrap();
+        assert_eq!(target.stream_key.as_deref(), Some("somekey"));
+        assert_eq!(target.url, "rtmp://example.com/live/target");
+        assert_eq!(target.host, "example.com");
+        assert_eq!(target.port, DEFAULT_PORT);
+        assert_eq!(target.app_name, "live");
+        assert_eq!(target.target_stream_key, "target");
+        assert_eq!(target.to_string(), "somekey=rtmp://example.com/live/target");
+    }
+
+    #[test]
+    fn parses_push_target_of_any_stream_key() {
+        let target = PushTarget::parse("rtmp://example.com:1936/app/instance/target").unwrap();
+        assert_eq!(target.stream_key, None);
+        assert_eq!(target.port, 1936);
+        assert_eq!(target.app_name, "app/instance");
+        assert_eq!(target.target_stream_key, "target");
+        assert_eq!(
+            target.to_string(),
+            "*=rtmp://example.com:1936/app/instance/target"
+        );
+
+        let target = PushTarget::parse("*=rtmp://example.com/live/target").unwrap();
+        assert_eq!(target.stream_key, None);
+
+        // The query string of the url is not taken for the stream key
+        let target = PushTarget::parse("rtmp://example.com/live/target?token=abc").unwrap();
+        assert_eq!(target.stream_key, None);
+        assert_eq!(target.target_stream_key, "target?token=abc");
+    }
+
+    #[test]
+    fn rejects_invalid_push_targets() {
+        for entry in &[
+            "somekey=http://example.com/live/target",
+            "rtmp://example.com",
+            "rtmp://example.com/live",
+            "rtmp://example.com/live/",
+            "rtmp://example.com//target",
+            "rtmp://example.com:port/live/target",
+        ] {
+            assert!(PushTarget::parse(entry).is_err(), "{} was accepted", entry);
+        }
+    }
+
+    #[test]
+    fn matches_stream_key() {
+        let target = PushTarget::parse("somekey=rtmp://example.com/live/target").unwrap();
+        assert!(target.matches("somekey"));
+        assert!(!target.matches("otherkey"));
+
+        let target = PushTarget::parse("*=rtmp://example.com/live/target").unwrap();
+        assert!(target.matches("somekey"));
+        assert!(target.matches("otherkey"));
+    }
+
+    #[test]
+    fn resyncs_after_dropping() {
+        let (sender, receiver) = sync_channel(2);
+        let relay = Relay {
+            sender,
+            dropped_headers: RefCell::new(None),
+        };
+
+        relay.send(media(false));
+        relay.send(media(false));
+        // Dropped as the queue is full, the sequence header is kept for later
+        relay.send(media(false));
+        relay.send(media(true));
+        relay.send(media(false));
+
+        for _ in 0..2 {
+            assert!(matches!(
+                receiver.try_recv(),
+                Ok(RelayInput::Media {
+                    is_sequence_header: false,
+                    ..
+                })
+            ));
+        }
+        assert!(receiver.try_recv().is_err());
+
+        relay.send(media(false));
+        match receiver.try_recv() {
+            Ok(RelayInput::Resync(headers)) => {
+                assert_eq!(headers.len(), 1);
+                assert!(headers[0].is_header());
+            }
+            _ => panic!("Expected a resync"),
+        }
+        assert!(matches!(receiver.try_recv(), Ok(RelayInput::Media { .. })));
+        assert!(relay.dropped_headers.borrow().is_none());
+    }
+}
@@ -1,21 +1,57 @@
 // Based on the example code in: https://github.com/KallDrexx/rust-media-libs/blob/master/examples/threaded_rtmp_server/src/server.rs
-use crate::data::{Media, MediaType, RtmpInput};
+use crate::auth;
+use crate::data::{ChannelKey, DataMessage, Media, MediaType, RtmpInput};
+use crate::flv;
+use crate::flv::{AudioPacket, AudioPacketType, VideoCodec, VideoPacket, VideoPacketType};
+use crate::queue::MediaQueue;
+use crate::relay::{PushTarget, Relay};
+use crate::stats::{Role, ServerStats};
 use bytes::Bytes;
-use rml_rtmp::chunk_io::Packet;
+use rml_amf0::Amf0Value;
+use rml_rtmp::chunk_io::{ChunkDeserializer, Packet};
+use rml_rtmp::messages::{MessagePayload, RtmpMessage};
 use rml_rtmp::sessions::StreamMetadata;
 use rml_rtmp::sessions::{
     ServerSession, ServerSessionConfig, ServerSessionEvent, ServerSessionResult,
 };
 use rml_rtmp::time::RtmpTimestamp;
 use slab::Slab;
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
-use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const AMF0_COMMAND_TYPE_ID: u8 = 20;
+const AMF0_STRING_MARKER: u8 = 0x02;
+const AMF0_OBJECT_MARKER: u8 = 0x03;
+const AMF0_STRICT_ARRAY_MARKER: u8 = 0x0a;
+const AMF0_OBJECT_END: [u8; 3] = [0x00, 0x00, 0x09];
+
+// The messages created outside of the session use a chunk stream the session never uses
+const OUT_OF_BAND_CHUNK_STREAM_ID: u8 = 20;
+const CHUNK_TYPE_CONTINUATION: u8 = 0xc0;
+const CHUNK_STREAM_ID_MASK: u8 = 0x3f;
+const MAX_CHUNK_TIMESTAMP: u32 = 0x00ff_ffff;
 
 enum ClientAction {
     Waiting,
-    Publishing(String), // Publishing to a stream key
-    Watching { stream_key: String, stream_id: u32 },
+    Publishing { channel: ChannelKey, stream_id: u32 },
+    Watching { channel: ChannelKey, stream_id: u32 },
+}
+
+/// Message of the input the session does not report by itself
+enum ReadMessage {
+    Data(DataMessage),
+    Connect {
+        transaction_id: f64,
+        tc_url: Option<String>,
+        flash_version: Option<String>,
+    },
+    /// The publish request of the session does not tell the stream it was sent on
+    Publish {
+        stream_id: u32,
+    },
 }
 
 enum ReceivedDataType {
@@ -25,18 +61,34 @@ enum ReceivedDataType {
 
 struct Client {
     session: ServerSession,
+    /// Reads the data messages the session drops, or reduces to a `StreamMetadata`, from a copy of
+    /// the input. Unset once the input could not be read, the session going on without it.
+    data_reader: Option<ChunkDeserializer>,
+    /// Parameters of the connect command, which the session does not report
+    tc_url: Option<String>,
+    flash_version: Option<String>,
+    /// Transaction of the connect command, answered outside of the session when rejected
+    connect_transaction_id: Option<f64>,
+    /// Stream of the latest publish command
+    publish_stream_id: Option<u32>,
     current_action: ClientAction,
     connection_id: usize,
     has_received_video_keyframe: bool,
 }
 
 impl Client {
+    /// Stream of the publish command, the session numbering the streams from 1 when the command
+    /// could not be read
+    fn publishing_stream_id(&self) -> u32 {
+        self.publish_stream_id.unwrap_or(1)
+    }
+
     fn get_active_stream_id(&self) -> Option<u32> {
         match self.current_action {
             ClientAction::Waiting => None,
-            ClientAction::Publishing(_) => None,
+            ClientAction::Publishing { .. } => None,
             ClientAction::Watching {
-                stream_key: _,
+                channel: _,
                 stream_id,
             } => Some(stream_id),
         }
@@ -47,8 +99,236 @@ struct MediaChannel {
     publishing_client_id: Option<usize>,
     watching_client_ids: HashSet<usize>,
     metadata: Option<Rc<StreamMetadata>>,
-    video_sequence_header: Option<Bytes>,
+    /// Sequence headers of each video codec seen, keyed by codec
+    video_sequence_headers: HashMap<VideoCodec, Bytes>,
+    /// Codec of the latest video sequence header
+    video_codec: Option<VideoCodec>,
     audio_sequence_header: Option<Bytes>,
+    /// Multichannel configuration of the Enhanced RTMP audio
+    audio_channel_config: Option<Bytes>,
+    has_received_keyframe: bool,
+    /// The audio only waits for a keyframe once the publisher sent video, audio only publishers
+    /// have their audio delivered right away
+    has_received_video: bool,
+    /// The subscription receiving the content published to this channel
+    subscription_id: Option<u64>,
+    /// Published by an element of the pipeline rather than by a RTMP client
+    has_local_publisher: bool,
+    /// Forwarding the published content to the push targets
+    relays: Vec<Relay>,
+}
+
+impl MediaChannel {
+    fn new() -> Self {
+        MediaChannel {
+            publishing_client_id: None,
+            watching_client_ids: HashSet::new(),
+            metadata: None,
+            video_sequence_headers: HashMap::new(),
+            video_codec: None,
+            audio_sequence_header: None,
+            audio_channel_config: None,
+            has_received_keyframe: false,
+            has_received_video: false,
+            subscription_id: None,
+            has_local_publisher: false,
+            relays: Vec::new(),
+        }
+    }
+
+    fn is_published(&self) -> bool {
+        self.publishing_client_id.is_some() || self.has_local_publisher
+    }
+
+    /// The sequence header of the codec currently in use
+    fn video_sequence_header(&self) -> Option<&Bytes> {
+        self.video_codec
+            .and_then(|codec| self.video_sequence_headers.get(&codec))
+    }
+
+    fn clear_sequence_headers(&mut self) {
+        self.video_sequence_headers.clear();
+        self.video_codec = None;
+        self.audio_sequence_header = None;
+        self.audio_channel_config = None;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    /// Application names clients are allowed to connect to, any name is accepted when empty
+    pub app_names: Vec<String>,
+    /// Stream keys publishers are allowed to use, any key is accepted when empty
+    pub stream_keys: Vec<String>,
+    /// Upstream servers the content published to the matching stream keys is forwarded to
+    pub push_targets: Vec<PushTarget>,
+    /// Publishers have to sign their stream keys with it when set
+    pub secret: Option<String>,
+    /// Connections which did not complete the RTMP handshake in time are closed
+    pub handshake_timeout: Option<Duration>,
+}
+
+impl ServerConfig {
+    fn accepts_app_name(&self, app_name: &str) -> bool {
+        self.app_names.is_empty() || self.app_names.iter().any(|name| name == app_name)
+    }
+
+    fn accepts_stream_key(&self, stream_key: &str) -> bool {
+        self.stream_keys.is_empty() || self.stream_keys.iter().any(|key| key == stream_key)
+    }
+
+    /// How specific the configuration is about the channels it accepts, a listed stream key
+    /// weighing more than a listed application name
+    fn specificity(&self) -> (bool, bool) {
+        (!self.stream_keys.is_empty(), !self.app_names.is_empty())
+    }
+
+    fn authorize(
+        &self,
+        app_name: &str,
+        stream_key: &str,
+        query: Option<&str>,
+    ) -> Result<(), String> {
+        match self.secret {
+            Some(ref secret) => auth::verify(secret, app_name, stream_key, query),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Lets the elements follow and take part in the lifecycle of the connections. The methods are
+/// called from the connections thread, which waits for them to return.
+pub trait ServerListener: Send + Sync {
+    fn client_connected(&self, connection_id: usize, address: &str);
+    /// The RTMP session does not expose the tcUrl and flashVer of the connect command yet
+    fn connect_requested(
+        &self,
+        connection_id: usize,
+        app_name: &str,
+        tc_url: Option<&str>,
+        flash_version: Option<&str>,
+    );
+    /// Returns whether the publisher is accepted
+    fn publish_requested(
+        &self,
+        connection_id: usize,
+        app_name: &str,
+        stream_key: &str,
+        mode: &str,
+    ) -> bool;
+    fn publish_ended(&self, app_name: &str, stream_key: &str);
+    fn client_disconnected(&self, connection_id: usize);
+    /// The connection is closed right after
+    fn handshake_timed_out(&self, connection_id: usize);
+}
+
+/// Content published to the channels accepted by the configuration goes to the queue
+pub struct Subscription {
+    pub config: ServerConfig,
+    pub queue: Arc<MediaQueue>,
+    pub listener: Arc<dyn ServerListener>,
+}
+
+/// The subscriptions of all the elements sharing a server
+#[derive(Default)]
+pub struct Subscriptions {
+    next_id: u64,
+    entries: HashMap<u64, Subscription>,
+}
+
+impl Subscriptions {
+    pub fn insert(&mut self, subscription: Subscription) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.insert(id, subscription);
+        id
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.entries.remove(&id);
+    }
+
+    fn accepts_app_name(&self, app_name: &str) -> bool {
+        self.entries
+            .values()
+            .any(|subscription| subscription.config.accepts_app_name(app_name))
+    }
+
+    /// The shortest handshake timeout of the elements sharing the server
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        self.entries
+            .values()
+            .filter_map(|subscription| subscription.config.handshake_timeout)
+            .min()
+    }
+
+    /// The subscription the channel is published to: the ones listing the app name or the stream
+    /// key are preferred over the ones accepting any, and the oldest one wins among equals
+    fn find(&self, app_name: &str, stream_key: &str) -> Option<u64> {
+        self.entries
+            .iter()
+            .filter(|(_, subscription)| {
+                subscription.config.accepts_app_name(app_name)
+                    && subscription.config.accepts_stream_key(stream_key)
+            })
+            .max_by_key(|(id, subscription)| (subscription.config.specificity(), Reverse(**id)))
+            .map(|(id, _)| *id)
+    }
+
+    fn authorize(
+        &self,
+        id: u64,
+        app_name: &str,
+        stream_key: &str,
+        query: Option<&str>,
+    ) -> Result<(), String> {
+        match self.entries.get(&id) {
+            Some(subscription) => subscription.config.authorize(app_name, stream_key, query),
+            None => Err("Unknown subscription".to_string()),
+        }
+    }
+
+    /// Starts forwarding the channel to the push targets of the subscription
+    fn start_relays(&self, id: u64, channel_key: &ChannelKey) -> Vec<Relay> {
+        let subscription = match self.entries.get(&id) {
+            Some(subscription) => subscription,
+            None => return Vec::new(),
+        };
+
+        subscription
+            .config
+            .push_targets
+            .iter()
+            .filter(|target| target.matches(&channel_key.stream_key))
+            .map(|target| {
+                println!("Forwarding {} to {}", channel_key, target.url);
+                Relay::start(
+                    target.clone(),
+                    channel_key.clone(),
+                    subscription.queue.clone(),
+                )
+            })
+            .collect()
+    }
+
+    fn listener(&self, id: u64) -> Option<Arc<dyn ServerListener>> {
+        self.entries
+            .get(&id)
+            .map(|subscription| subscription.listener.clone())
+    }
+
+    fn listeners(&self) -> Vec<Arc<dyn ServerListener>> {
+        self.entries
+            .values()
+            .map(|subscription| subscription.listener.clone())
+            .collect()
+    }
+
+    fn send(&self, id: Option<u64>, input: RtmpInput) {
+        if let Some(subscription) = id.and_then(|id| self.entries.get(&id)) {
+            subscription.queue.push(input);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -65,19 +345,19 @@ pub enum ServerResult {
 pub struct Server {
     clients: Slab<Client>,
     connection_to_client_map: HashMap<usize, usize>,
-    channels: HashMap<String, MediaChannel>,
-    media_sink: Sender<RtmpInput>,
-    has_received_keyframe: bool,
+    channels: HashMap<ChannelKey, MediaChannel>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    stats: Arc<Mutex<ServerStats>>,
 }
 
 impl Server {
-    pub fn new(media_sink: Sender<RtmpInput>) -> Self {
+    pub fn new(subscriptions: Arc<Mutex<Subscriptions>>, stats: Arc<Mutex<ServerStats>>) -> Self {
         Self {
             clients: Slab::with_capacity(8),
             connection_to_client_map: HashMap::with_capacity(8),
             channels: HashMap::new(),
-            media_sink,
-            has_received_keyframe: false,
+            subscriptions,
+            stats,
         }
     }
 
@@ -103,6 +383,11 @@ impl Server {
             );
             let client = Client {
                 session,
+                data_reader: Some(ChunkDeserializer::new()),
+                tc_url: None,
+                flash_version: None,
+                connect_transaction_id: None,
+                publish_stream_id: None,
                 connection_id,
                 current_action: ClientAction::Waiting,
                 has_received_video_keyframe: false,
@@ -114,6 +399,7 @@ impl Server {
         }
 
         let client_results: Vec<ServerSessionResult>;
+        let mut data_messages = Vec::new();
         {
             let client_id = self.connection_to_client_map.get(&connection_id).unwrap();
             let client = self.clients.get_mut(*client_id).unwrap();
@@ -121,29 +407,222 @@ impl Server {
                 Ok(results) => results,
                 Err(error) => return Err(error.to_string()),
             };
+
+            // Read before the session results, which include the connection request
+            let messages = match client.data_reader {
+                Some(ref mut reader) => read_messages(reader, bytes),
+                None => Ok(Vec::new()),
+            };
+            let messages = messages.unwrap_or_else(|error| {
+                println!(
+                    "Stopped reading the data messages of connection {}: {}",
+                    connection_id, error
+                );
+                client.data_reader = None;
+                Vec::new()
+            });
+            for message in messages {
+                match message {
+                    ReadMessage::Data(message) => data_messages.push(message),
+                    ReadMessage::Connect {
+                        transaction_id,
+                        tc_url,
+                        flash_version,
+                    } => {
+                        client.connect_transaction_id = Some(transaction_id);
+                        client.tc_url = tc_url;
+                        client.flash_version = flash_version;
+                    }
+                    ReadMessage::Publish { stream_id } => {
+                        client.publish_stream_id = Some(stream_id)
+                    }
+                }
+            }
         }
 
         self.handle_session_results(connection_id, client_results, &mut server_results);
+        for message in data_messages {
+            self.handle_data_received(connection_id, message);
+        }
         Ok(server_results)
     }
 
+    fn handle_data_received(&mut self, connection_id: usize, message: DataMessage) {
+        let client = match self
+            .connection_to_client_map
+            .get(&connection_id)
+            .and_then(|client_id| self.clients.get(*client_id))
+        {
+            Some(client) => client,
+            None => return,
+        };
+
+        let channel_key = match client.current_action {
+            ClientAction::Publishing {
+                channel: ref channel_key,
+                ..
+            } => channel_key,
+            _ => return,
+        };
+
+        if let Some(channel) = self.channels.get(channel_key) {
+            self.subscriptions.lock().unwrap().send(
+                channel.subscription_id,
+                RtmpInput::Data(channel_key.clone(), message),
+            );
+        }
+    }
+
+    pub fn notify_connection_opened(&mut self, connection_id: usize, address: &str) {
+        self.stats
+            .lock()
+            .unwrap()
+            .connection_opened(connection_id, address);
+
+        // Listeners are called without holding the lock, they may use the element
+        let listeners = self.subscriptions.lock().unwrap().listeners();
+        for listener in listeners {
+            listener.client_connected(connection_id, address);
+        }
+    }
+
+    pub fn notify_handshake_timed_out(&mut self, connection_id: usize) {
+        let listeners = self.subscriptions.lock().unwrap().listeners();
+        for listener in listeners {
+            listener.handshake_timed_out(connection_id);
+        }
+    }
+
+    /// Closes the connection of the client publishing to the channel, if any
+    pub fn disconnect_publisher(&mut self, channel_key: &ChannelKey) -> Vec<ServerResult> {
+        let client_id = match self
+            .channels
+            .get(channel_key)
+            .and_then(|channel| channel.publishing_client_id)
+        {
+            Some(client_id) => client_id,
+            None => return Vec::new(),
+        };
+
+        match self.clients.get(client_id) {
+            Some(client) => {
+                println!("Disconnecting the publisher of {}", channel_key);
+                vec![ServerResult::DisconnectConnection {
+                    connection_id: client.connection_id,
+                }]
+            }
+            None => Vec::new(),
+        }
+    }
+
     pub fn notify_connection_closed(&mut self, connection_id: usize) {
+        self.stats.lock().unwrap().connection_closed(connection_id);
+
+        let listeners = self.subscriptions.lock().unwrap().listeners();
+        for listener in listeners {
+            listener.client_disconnected(connection_id);
+        }
+
         match self.connection_to_client_map.remove(&connection_id) {
             None => (),
             Some(client_id) => {
                 let client = self.clients.remove(client_id);
                 match client.current_action {
-                    ClientAction::Publishing(stream_key) => self.publishing_ended(stream_key),
+                    ClientAction::Publishing { channel, .. } => self.publishing_ended(channel),
                     ClientAction::Watching {
-                        stream_key,
+                        channel,
                         stream_id: _,
-                    } => self.play_ended(client_id, stream_key),
+                    } => self.play_ended(client_id, channel),
                     ClientAction::Waiting => (),
                 }
             }
         }
     }
 
+    /// Lets every client know the stream they are publishing or watching is over
+    pub fn shutdown(&mut self) -> Vec<ServerResult> {
+        let mut server_results = Vec::new();
+
+        for (_, client) in self.clients.iter() {
+            let packet = match client.current_action {
+                ClientAction::Waiting => continue,
+                ClientAction::Publishing { stream_id, .. } => status_packet(
+                    stream_id,
+                    "status",
+                    "NetStream.Unpublish.Success",
+                    "Server is shutting down",
+                ),
+                ClientAction::Watching { stream_id, .. } => status_packet(
+                    stream_id,
+                    "status",
+                    "NetStream.Play.UnpublishNotify",
+                    "Server is shutting down",
+                ),
+            };
+
+            match packet {
+                Ok(packet) => server_results.push(ServerResult::OutboundPacket {
+                    target_connection_id: client.connection_id,
+                    packet,
+                }),
+                Err(error) => println!(
+                    "Error creating shutdown status for connection {}: {}",
+                    client.connection_id, error
+                ),
+            }
+        }
+
+        server_results
+    }
+
+    /// Handles the content published by the elements of the pipeline, which is distributed to the
+    /// watching clients like the content of RTMP publishers
+    pub fn local_input(&mut self, input: RtmpInput) -> Vec<ServerResult> {
+        let mut server_results = Vec::new();
+
+        match input {
+            RtmpInput::PublishStarted(channel_key) => {
+                println!("Local publishing started on {}", channel_key);
+                let channel = self
+                    .channels
+                    .entry(channel_key)
+                    .or_insert_with(MediaChannel::new);
+                channel.has_local_publisher = true;
+            }
+
+            RtmpInput::Metadata(channel_key, metadata) => {
+                self.handle_metadata_received(channel_key, metadata, &mut server_results);
+            }
+
+            RtmpInput::Media(channel_key, media) => {
+                let data_type = match media.media_type {
+                    MediaType::Video => ReceivedDataType::Video,
+                    MediaType::Audio => ReceivedDataType::Audio,
+                };
+                self.handle_audio_video_data_received(
+                    channel_key,
+                    RtmpTimestamp::new(media.timestamp),
+                    media.data,
+                    data_type,
+                    &mut server_results,
+                );
+            }
+
+            RtmpInput::PushStatus(..) | RtmpInput::Data(..) => (),
+
+            RtmpInput::PublishEnded(channel_key) => {
+                println!("Local publishing ended on {}", channel_key);
+                if let Some(channel) = self.channels.get_mut(&channel_key) {
+                    channel.has_local_publisher = false;
+                    channel.clear_sequence_headers();
+                }
+                self.publishing_ended(channel_key);
+            }
+        }
+
+        server_results
+    }
+
     fn handle_session_results(
         &mut self,
         executed_connection_id: usize,
@@ -191,13 +670,17 @@ impl Server {
                 request_id,
                 app_name,
                 stream_key,
-                mode: _,
+                mode,
             } => {
+                // The mode type is not exported by the session, its variants are named like the
+                // modes of the publish command
+                let mode = format!("{:?}", mode).to_lowercase();
                 self.handle_publish_requested(
                     executed_connection_id,
                     request_id,
                     app_name,
                     stream_key,
+                    &mode,
                     server_results,
                 );
             }
@@ -226,17 +709,18 @@ impl Server {
                 stream_key,
                 metadata,
             } => {
-                self.handle_metadata_received(app_name, stream_key, metadata, server_results);
+                let channel = published_channel(app_name, &stream_key);
+                self.handle_metadata_received(channel, metadata, server_results);
             }
 
             ServerSessionEvent::VideoDataReceived {
-                app_name: _,
+                app_name,
                 stream_key,
                 data,
                 timestamp,
             } => {
                 self.handle_audio_video_data_received(
-                    stream_key,
+                    published_channel(app_name, &stream_key),
                     timestamp,
                     data,
                     ReceivedDataType::Video,
@@ -245,13 +729,13 @@ impl Server {
             }
 
             ServerSessionEvent::AudioDataReceived {
-                app_name: _,
+                app_name,
                 stream_key,
                 data,
                 timestamp,
             } => {
                 self.handle_audio_video_data_received(
-                    stream_key,
+                    published_channel(app_name, &stream_key),
                     timestamp,
                     data,
                     ReceivedDataType::Audio,
@@ -278,6 +762,53 @@ impl Server {
             requested_connection_id, app_name
         );
 
+        let listeners: Vec<_> = {
+            let subscriptions = self.subscriptions.lock().unwrap();
+            subscriptions
+                .entries
+                .values()
+                .filter(|subscription| subscription.config.accepts_app_name(&app_name))
+                .map(|subscription| subscription.listener.clone())
+                .collect()
+        };
+        let (tc_url, flash_version) = match self
+            .connection_to_client_map
+            .get(&requested_connection_id)
+            .and_then(|client_id| self.clients.get(*client_id))
+        {
+            Some(client) => (client.tc_url.clone(), client.flash_version.clone()),
+            None => (None, None),
+        };
+        for listener in listeners {
+            listener.connect_requested(
+                requested_connection_id,
+                &app_name,
+                tc_url.as_deref(),
+                flash_version.as_deref(),
+            );
+        }
+
+        let is_local_app = self
+            .channels
+            .iter()
+            .any(|(key, channel)| channel.has_local_publisher && key.app_name == app_name);
+        if !is_local_app
+            && !self
+                .subscriptions
+                .lock()
+                .unwrap()
+                .accepts_app_name(&app_name)
+        {
+            println!("Application '{}' is not accepted", app_name);
+            self.reject_connection(
+                requested_connection_id,
+                "NetConnection.Connect.Rejected",
+                "Unknown application",
+                server_results,
+            );
+            return;
+        }
+
         let accept_result;
         {
             let client_id = self
@@ -297,6 +828,22 @@ impl Server {
             }
 
             Ok(results) => {
+                // The session builds the connect response by itself, the codecs are added to it
+                let results = results
+                    .into_iter()
+                    .map(|result| match result {
+                        ServerSessionResult::OutboundResponse(packet) => {
+                            match with_fourcc_list(&packet) {
+                                Ok(packet) => ServerSessionResult::OutboundResponse(packet),
+                                Err(error) => {
+                                    println!("Error adding the FourCC list: {}", error);
+                                    ServerSessionResult::OutboundResponse(packet)
+                                }
+                            }
+                        }
+                        result => result,
+                    })
+                    .collect();
                 self.handle_session_results(requested_connection_id, results, server_results);
             }
         }
@@ -308,6 +855,7 @@ impl Server {
         request_id: u32,
         app_name: String,
         stream_key: String,
+        mode: &str,
         server_results: &mut Vec<ServerResult>,
     ) {
         println!(
@@ -315,18 +863,84 @@ impl Server {
             app_name, stream_key
         );
 
-        match self.channels.get(&stream_key) {
-            None => (),
-            Some(channel) => match channel.publishing_client_id {
-                None => (),
-                Some(_) => {
-                    println!("Stream key already being published to");
-                    server_results.push(ServerResult::DisconnectConnection {
-                        connection_id: requested_connection_id,
-                    });
-                    return;
-                }
-            },
+        // The query string carries the authentication, it is not part of the channel name
+        let (name, query) = auth::split_stream_key(&stream_key);
+        let (stream_key, query) = (name.to_string(), query.map(str::to_string));
+
+        let subscription_id = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .find(&app_name, &stream_key);
+        let subscription_id = match subscription_id {
+            Some(id) => id,
+            None => {
+                println!("Stream key '{}' is not accepted", stream_key);
+                self.reject_publishing(
+                    requested_connection_id,
+                    "NetStream.Publish.BadName",
+                    "Invalid stream key",
+                    server_results,
+                );
+                return;
+            }
+        };
+
+        let authorization = self.subscriptions.lock().unwrap().authorize(
+            subscription_id,
+            &app_name,
+            &stream_key,
+            query.as_deref(),
+        );
+        if let Err(error) = authorization {
+            println!(
+                "Publishing to '{}' is not authorized: {}",
+                stream_key, error
+            );
+            self.reject_publishing(
+                requested_connection_id,
+                "NetStream.Publish.BadName",
+                "Invalid or expired token",
+                server_results,
+            );
+            return;
+        }
+
+        let channel_key = ChannelKey {
+            app_name,
+            stream_key,
+        };
+
+        match self.channels.get(&channel_key) {
+            Some(channel) if channel.is_published() => {
+                println!("Stream key already being published to");
+                server_results.push(ServerResult::DisconnectConnection {
+                    connection_id: requested_connection_id,
+                });
+                return;
+            }
+            _ => (),
+        }
+
+        // The handlers are only asked about the publishers passing the built-in checks
+        let listener = self.subscriptions.lock().unwrap().listener(subscription_id);
+        let is_accepted = listener.map_or(true, |listener| {
+            listener.publish_requested(
+                requested_connection_id,
+                &channel_key.app_name,
+                &channel_key.stream_key,
+                mode,
+            )
+        });
+        if !is_accepted {
+            println!("Publishing to '{}' was denied", channel_key.stream_key);
+            self.reject_publishing(
+                requested_connection_id,
+                "NetStream.Publish.Denied",
+                "Publishing denied",
+                server_results,
+            );
+            return;
         }
 
         let accept_result;
@@ -336,17 +950,28 @@ impl Server {
                 .get(&requested_connection_id)
                 .unwrap();
             let client = self.clients.get_mut(*client_id).unwrap();
-            client.current_action = ClientAction::Publishing(stream_key.clone());
-
-            let channel = self.channels.entry(stream_key).or_insert(MediaChannel {
-                publishing_client_id: None,
-                watching_client_ids: HashSet::new(),
-                metadata: None,
-                video_sequence_header: None,
-                audio_sequence_header: None,
-            });
+            client.current_action = ClientAction::Publishing {
+                channel: channel_key.clone(),
+                stream_id: client.publishing_stream_id(),
+            };
+            self.stats.lock().unwrap().role_changed(
+                requested_connection_id,
+                Role::Publisher,
+                &channel_key,
+            );
+
+            let channel = self
+                .channels
+                .entry(channel_key.clone())
+                .or_insert_with(MediaChannel::new);
 
             channel.publishing_client_id = Some(*client_id);
+            channel.subscription_id = Some(subscription_id);
+            channel.relays = self
+                .subscriptions
+                .lock()
+                .unwrap()
+                .start_relays(subscription_id, &channel_key);
             accept_result = client.session.accept_request(request_id);
         }
 
@@ -359,11 +984,68 @@ impl Server {
             }
 
             Ok(results) => {
+                self.subscriptions.lock().unwrap().send(
+                    Some(subscription_id),
+                    RtmpInput::PublishStarted(channel_key),
+                );
                 self.handle_session_results(requested_connection_id, results, server_results);
             }
         }
     }
 
+    /// Sends the error response of the connect command to the client and disconnects it, the
+    /// session only being able to accept requests
+    fn reject_connection(
+        &mut self,
+        connection_id: usize,
+        code: &str,
+        description: &str,
+        server_results: &mut Vec<ServerResult>,
+    ) {
+        let transaction_id = self
+            .connection_to_client_map
+            .get(&connection_id)
+            .and_then(|client_id| self.clients.get(*client_id))
+            .and_then(|client| client.connect_transaction_id)
+            .unwrap_or(1.0);
+        let packet = command_packet(0, "_error", transaction_id, "error", code, description);
+        self.reject(connection_id, packet, server_results);
+    }
+
+    /// Sends the error status of the publish command to the client and disconnects it
+    fn reject_publishing(
+        &mut self,
+        connection_id: usize,
+        code: &str,
+        description: &str,
+        server_results: &mut Vec<ServerResult>,
+    ) {
+        let stream_id = self
+            .connection_to_client_map
+            .get(&connection_id)
+            .and_then(|client_id| self.clients.get(*client_id))
+            .map_or(1, |client| client.publishing_stream_id());
+        let packet = status_packet(stream_id, "error", code, description);
+        self.reject(connection_id, packet, server_results);
+    }
+
+    fn reject(
+        &mut self,
+        connection_id: usize,
+        packet: Result<Packet, String>,
+        server_results: &mut Vec<ServerResult>,
+    ) {
+        match packet {
+            Ok(packet) => server_results.push(ServerResult::OutboundPacket {
+                target_connection_id: connection_id,
+                packet,
+            }),
+            Err(error) => println!("Error occurred rejecting request: {}", error),
+        }
+
+        server_results.push(ServerResult::DisconnectConnection { connection_id });
+    }
+
     fn handle_play_requested(
         &mut self,
         requested_connection_id: usize,
@@ -385,21 +1067,24 @@ impl Server {
                 .get(&requested_connection_id)
                 .unwrap();
             let client = self.clients.get_mut(*client_id).unwrap();
+            let channel_key = ChannelKey {
+                app_name,
+                stream_key,
+            };
             client.current_action = ClientAction::Watching {
-                stream_key: stream_key.clone(),
+                channel: channel_key.clone(),
                 stream_id,
             };
+            self.stats.lock().unwrap().role_changed(
+                requested_connection_id,
+                Role::Watcher,
+                &channel_key,
+            );
 
             let channel = self
                 .channels
-                .entry(stream_key.clone())
-                .or_insert(MediaChannel {
-                    publishing_client_id: None,
-                    watching_client_ids: HashSet::new(),
-                    metadata: None,
-                    video_sequence_header: None,
-                    audio_sequence_header: None,
-                });
+                .entry(channel_key)
+                .or_insert_with(MediaChannel::new);
 
             channel.watching_client_ids.insert(*client_id);
             accept_result = match client.session.accept_request(request_id) {
@@ -430,9 +1115,9 @@ impl Server {
                     }
 
                     // If the channel already has sequence headers, send them
-                    match channel.video_sequence_header {
+                    match channel.video_sequence_header() {
                         None => (),
-                        Some(ref data) => {
+                        Some(data) => {
                             let packet = match client.session.send_video_data(
                                 stream_id,
                                 data.clone(),
@@ -457,31 +1142,33 @@ impl Server {
                         }
                     }
 
-                    match channel.audio_sequence_header {
-                        None => (),
-                        Some(ref data) => {
-                            let packet = match client.session.send_audio_data(
-                                stream_id,
-                                data.clone(),
-                                RtmpTimestamp::new(0),
-                                false,
-                            ) {
-                                Ok(packet) => packet,
-                                Err(error) => {
-                                    println!(
-                                        "Error occurred sending audio header to new client: {:?}",
-                                        error
-                                    );
-                                    server_results.push(ServerResult::DisconnectConnection {
-                                        connection_id: requested_connection_id,
-                                    });
-
-                                    return;
-                                }
-                            };
-
-                            results.push(ServerSessionResult::OutboundResponse(packet));
-                        }
+                    // The channel layout has to follow the sequence start it applies to
+                    let audio_headers = channel
+                        .audio_sequence_header
+                        .iter()
+                        .chain(channel.audio_channel_config.iter());
+                    for data in audio_headers {
+                        let packet = match client.session.send_audio_data(
+                            stream_id,
+                            data.clone(),
+                            RtmpTimestamp::new(0),
+                            false,
+                        ) {
+                            Ok(packet) => packet,
+                            Err(error) => {
+                                println!(
+                                    "Error occurred sending audio header to new client: {:?}",
+                                    error
+                                );
+                                server_results.push(ServerResult::DisconnectConnection {
+                                    connection_id: requested_connection_id,
+                                });
+
+                                return;
+                            }
+                        };
+
+                        results.push(ServerSessionResult::OutboundResponse(packet));
                     }
 
                     Ok(results)
@@ -507,23 +1194,26 @@ impl Server {
 
     fn handle_metadata_received(
         &mut self,
-        app_name: String,
-        stream_key: String,
+        channel_key: ChannelKey,
         metadata: StreamMetadata,
         server_results: &mut Vec<ServerResult>,
     ) {
         println!(
             "New metadata received for app '{}' and stream key '{}'",
-            app_name, stream_key
+            channel_key.app_name, channel_key.stream_key
         );
-        let channel = match self.channels.get_mut(&stream_key) {
+        let channel = match self.channels.get_mut(&channel_key) {
             Some(channel) => channel,
             None => return,
         };
 
-        self.media_sink
-            .send(RtmpInput::Metadata(metadata.clone()))
-            .unwrap();
+        self.subscriptions.lock().unwrap().send(
+            channel.subscription_id,
+            RtmpInput::Metadata(channel_key.clone(), metadata.clone()),
+        );
+        for relay in &channel.relays {
+            relay.send_metadata(metadata.clone());
+        }
         let metadata = Rc::new(metadata);
         channel.metadata = Some(metadata.clone());
         // Send the metadata to all current watchers
@@ -562,70 +1252,126 @@ impl Server {
 
     fn handle_audio_video_data_received(
         &mut self,
-        stream_key: String,
+        channel_key: ChannelKey,
         timestamp: RtmpTimestamp,
         data: Bytes,
         data_type: ReceivedDataType,
         server_results: &mut Vec<ServerResult>,
     ) {
-        let channel = match self.channels.get_mut(&stream_key) {
+        let channel = match self.channels.get_mut(&channel_key) {
             Some(channel) => channel,
             None => return,
         };
 
         // If this is an audio or video sequence header we need to save it, so it can be
         // distributed to any late coming watchers
-        match data_type {
-            ReceivedDataType::Video => {
-                if is_video_sequence_header(data.clone()) {
-                    channel.video_sequence_header = Some(data.clone());
+        let is_sequence_header = match data_type {
+            ReceivedDataType::Video => match VideoPacket::parse(&data) {
+                Some(packet) if packet.packet_type == VideoPacketType::SequenceHeader => {
+                    channel.video_codec = Some(packet.codec);
+                    channel
+                        .video_sequence_headers
+                        .insert(packet.codec, data.clone());
+                    true
                 }
-            }
+                _ => false,
+            },
 
             ReceivedDataType::Audio => {
-                if is_audio_sequence_header(data.clone()) {
-                    channel.audio_sequence_header = Some(data.clone());
+                match AudioPacket::parse(&data).map(|packet| packet.packet_type) {
+                    Some(AudioPacketType::SequenceHeader) => {
+                        channel.audio_sequence_header = Some(data.clone());
+                        // A new sequence start resets the layout of the previous one
+                        channel.audio_channel_config = None;
+                        true
+                    }
+                    Some(AudioPacketType::MultichannelConfig) => {
+                        channel.audio_channel_config = Some(data.clone());
+                        true
+                    }
+                    _ => false,
                 }
             }
+        };
+
+        let media_type = match data_type {
+            ReceivedDataType::Video => MediaType::Video,
+            ReceivedDataType::Audio => MediaType::Audio,
+        };
+        let is_keyframe = match data_type {
+            ReceivedDataType::Video => is_video_keyframe(data.clone()),
+            ReceivedDataType::Audio => false,
+        };
+        let mut stats = self.stats.lock().unwrap();
+        let publisher = match channel.publishing_client_id {
+            Some(client_id) => self.clients.get(client_id),
+            None => None,
+        };
+        if let Some(connection) =
+            publisher.and_then(|client| stats.connection_mut(client.connection_id))
+        {
+            connection.media(media_type, data.len(), timestamp.value, is_keyframe);
+        }
+
+        for relay in &channel.relays {
+            relay.send_media(
+                media_type,
+                data.clone(),
+                timestamp.value,
+                is_sequence_header,
+            );
+        }
+
+        if let ReceivedDataType::Video = data_type {
+            channel.has_received_video = true;
         }
 
         // send to gstreamer element
         {
             let should_send_to_client = match data_type {
                 ReceivedDataType::Video => {
-                    self.has_received_keyframe
+                    channel.has_received_keyframe
                         || (is_video_sequence_header(data.clone())
                             || is_video_keyframe(data.clone()))
                 }
 
                 ReceivedDataType::Audio => {
-                    self.has_received_keyframe || is_audio_sequence_header(data.clone())
+                    !channel.has_received_video
+                        || channel.has_received_keyframe
+                        || is_audio_sequence_header(data.clone())
                 }
             };
             if should_send_to_client {
                 match data_type {
-                    ReceivedDataType::Audio => self
-                        .media_sink
-                        .send(RtmpInput::Media(Media {
-                            media_type: MediaType::Audio,
-                            data: data.clone(),
-                            timestamp: timestamp.value,
-                            can_be_dropped: true,
-                        }))
-                        .unwrap(),
+                    ReceivedDataType::Audio => self.subscriptions.lock().unwrap().send(
+                        channel.subscription_id,
+                        RtmpInput::Media(
+                            channel_key.clone(),
+                            Media {
+                                media_type: MediaType::Audio,
+                                data: data.clone(),
+                                timestamp: timestamp.value,
+                                can_be_dropped: !is_audio_sequence_header(data.clone()),
+                            },
+                        ),
+                    ),
                     ReceivedDataType::Video => {
                         if is_video_keyframe(data.clone()) {
-                            self.has_received_keyframe = true;
+                            channel.has_received_keyframe = true;
                         }
 
-                        self.media_sink
-                            .send(RtmpInput::Media(Media {
-                                media_type: MediaType::Video,
-                                data: data.clone(),
-                                timestamp: timestamp.value,
-                                can_be_dropped: true,
-                            }))
-                            .unwrap();
+                        self.subscriptions.lock().unwrap().send(
+                            channel.subscription_id,
+                            RtmpInput::Media(
+                                channel_key.clone(),
+                                Media {
+                                    media_type: MediaType::Video,
+                                    data: data.clone(),
+                                    timestamp: timestamp.value,
+                                    can_be_dropped: !is_video_sequence_header(data.clone()),
+                                },
+                            ),
+                        );
                     }
                 };
             }
@@ -650,11 +1396,16 @@ impl Server {
                 }
 
                 ReceivedDataType::Audio => {
-                    client.has_received_video_keyframe || is_audio_sequence_header(data.clone())
+                    !channel.has_received_video
+                        || client.has_received_video_keyframe
+                        || is_audio_sequence_header(data.clone())
                 }
             };
 
             if !should_send_to_client {
+                if let Some(connection) = stats.connection_mut(client.connection_id) {
+                    connection.dropped_frames += 1;
+                }
                 continue;
             }
 
@@ -680,10 +1431,15 @@ impl Server {
             };
 
             match send_result {
-                Ok(packet) => server_results.push(ServerResult::OutboundPacket {
-                    target_connection_id: client.connection_id,
-                    packet,
-                }),
+                Ok(packet) => {
+                    if let Some(connection) = stats.connection_mut(client.connection_id) {
+                        connection.media(media_type, data.len(), timestamp.value, is_keyframe);
+                    }
+                    server_results.push(ServerResult::OutboundPacket {
+                        target_connection_id: client.connection_id,
+                        packet,
+                    })
+                }
 
                 Err(error) => {
                     println!(
@@ -698,18 +1454,35 @@ impl Server {
         }
     }
 
-    fn publishing_ended(&mut self, stream_key: String) {
-        let channel = match self.channels.get_mut(&stream_key) {
+    fn publishing_ended(&mut self, channel_key: ChannelKey) {
+        let channel = match self.channels.get_mut(&channel_key) {
             Some(channel) => channel,
             None => return,
         };
 
         channel.publishing_client_id = None;
         channel.metadata = None;
+        channel.has_received_keyframe = false;
+        channel.has_received_video = false;
+        // Dropping the relays stops them
+        channel.relays.clear();
+
+        let subscription_id = channel.subscription_id.take();
+        let listener = {
+            let subscriptions = self.subscriptions.lock().unwrap();
+            subscriptions.send(
+                subscription_id,
+                RtmpInput::PublishEnded(channel_key.clone()),
+            );
+            subscription_id.and_then(|id| subscriptions.listener(id))
+        };
+        if let Some(listener) = listener {
+            listener.publish_ended(&channel_key.app_name, &channel_key.stream_key);
+        }
     }
 
-    fn play_ended(&mut self, client_id: usize, stream_key: String) {
-        let channel = match self.channels.get_mut(&stream_key) {
+    fn play_ended(&mut self, client_id: usize, channel_key: ChannelKey) {
+        let channel = match self.channels.get_mut(&channel_key) {
             Some(channel) => channel,
             None => return,
         };
@@ -718,17 +1491,548 @@ impl Server {
     }
 }
 
+impl Drop for Server {
+    fn drop(&mut self) {
+        // Also reached when the connections thread panics, so the elements do not wait forever
+        for subscription in self.subscriptions.lock().unwrap().entries.values() {
+            subscription.queue.close();
+        }
+    }
+}
+
+/// The channel of the events of a publisher, the session reporting the stream key along with the
+/// query string of its signature
+fn published_channel(app_name: String, stream_key: &str) -> ChannelKey {
+    ChannelKey {
+        app_name,
+        stream_key: auth::split_stream_key(stream_key).0.to_string(),
+    }
+}
+
+/// Creates an onStatus message outside of the session, for the notifications the session does not
+/// provide by itself
+fn status_packet(
+    stream_id: u32,
+    level: &str,
+    code: &str,
+    description: &str,
+) -> Result<Packet, String> {
+    command_packet(stream_id, "onStatus", 0.0, level, code, description)
+}
+
+/// Creates a command carrying a status object outside of the session
+fn command_packet(
+    stream_id: u32,
+    command_name: &str,
+    transaction_id: f64,
+    level: &str,
+    code: &str,
+    description: &str,
+) -> Result<Packet, String> {
+    let mut info = HashMap::new();
+    info.insert(
+        "level".to_string(),
+        Amf0Value::Utf8String(level.to_string()),
+    );
+    info.insert("code".to_string(), Amf0Value::Utf8String(code.to_string()));
+    info.insert(
+        "description".to_string(),
+        Amf0Value::Utf8String(description.to_string()),
+    );
+
+    let message = RtmpMessage::Amf0Command {
+        command_name: command_name.to_string(),
+        transaction_id,
+        command_object: Amf0Value::Null,
+        additional_arguments: vec![Amf0Value::Object(info)],
+    };
+
+    let payload = message
+        .into_message_payload(RtmpTimestamp::new(0), stream_id)
+        .map_err(|error| format!("{:?}", error))?;
+    Ok(out_of_band_packet(&payload))
+}
+
+/// Splits a message created outside of the session into chunks of its own chunk stream, so that
+/// the header compression state of the session's chunk streams is left alone. Every chunk stream
+/// starts with a full header, the following chunks of the message only carry the basic header.
+fn out_of_band_packet(payload: &MessagePayload) -> Packet {
+    let timestamp = payload.timestamp.value;
+    let extended_timestamp = match timestamp >= MAX_CHUNK_TIMESTAMP {
+        true => Some(timestamp.to_be_bytes()),
+        false => None,
+    };
+
+    let mut bytes = Vec::with_capacity(payload.data.len() + 16);
+    bytes.push(OUT_OF_BAND_CHUNK_STREAM_ID);
+    bytes.extend_from_slice(&timestamp.min(MAX_CHUNK_TIMESTAMP).to_be_bytes()[1..]);
+    bytes.extend_from_slice(&(payload.data.len() as u32).to_be_bytes()[1..]);
+    bytes.push(payload.type_id);
+    bytes.extend_from_slice(&payload.message_stream_id.to_le_bytes());
+    append_chunks(
+        &mut bytes,
+        OUT_OF_BAND_CHUNK_STREAM_ID,
+        extended_timestamp.as_ref().map(|timestamp| &timestamp[..]),
+        &payload.data,
+    );
+
+    Packet {
+        bytes,
+        can_be_dropped: false,
+    }
+}
+
+/// Appends the data after the header of its first chunk, the following chunks of the message only
+/// carry the basic header and the extended timestamp
+fn append_chunks(
+    bytes: &mut Vec<u8>,
+    chunk_stream_id: u8,
+    extended_timestamp: Option<&[u8]>,
+    data: &[u8],
+) {
+    // The peer expects chunks of the size the session announced
+    let chunk_size = ServerSessionConfig::new().chunk_size as usize;
+    for (index, chunk) in data.chunks(chunk_size).enumerate() {
+        if index > 0 {
+            bytes.push(CHUNK_TYPE_CONTINUATION | chunk_stream_id);
+        }
+        if let Some(extended_timestamp) = extended_timestamp {
+            bytes.extend_from_slice(extended_timestamp);
+        }
+        bytes.extend_from_slice(chunk);
+    }
+}
+
+/// Adds the FourCCs of the Enhanced RTMP video codecs to the properties of the connect response the
+/// session built, as a `fourCcList` strict array which the AMF0 serializer does not support.
+///
+/// The response keeps its chunk header and only its length changes. The session compresses the
+/// following headers of the chunk stream against the original length, which its much shorter
+/// command responses never share, so they still carry their own length.
+fn with_fourcc_list(packet: &Packet) -> Result<Packet, String> {
+    let bytes = &packet.bytes;
+    // Full or compressed header without the message stream id, after a one byte basic header
+    let header_length = match bytes.first().map(|byte| byte >> 6) {
+        Some(0) => 12,
+        Some(1) => 8,
+        _ => return Err("Unexpected chunk header".to_string()),
+    };
+    if bytes.len() < header_length || bytes[0] & CHUNK_STREAM_ID_MASK < 2 {
+        return Err("Unexpected chunk header".to_string());
+    }
+    if bytes[7] != AMF0_COMMAND_TYPE_ID {
+        return Err(format!("Unexpected message type {}", bytes[7]));
+    }
+    let extended_timestamp = match bytes[1..4] == [0xff; 3] {
+        true => bytes.get(header_length..header_length + 4),
+        false => None,
+    };
+    let data_start = header_length + extended_timestamp.map_or(0, <[u8]>::len);
+    let length = u32::from_be_bytes([0, bytes[4], bytes[5], bytes[6]]) as usize;
+    if bytes.len() != data_start + length || length > ServerSessionConfig::new().chunk_size as usize
+    {
+        return Err("The response does not fit in a single chunk".to_string());
+    }
+
+    let values =
+        rml_amf0::deserialize(&mut &bytes[data_start..]).map_err(|error| format!("{:?}", error))?;
+    let (properties, info) = match values.as_slice() {
+        [Amf0Value::Utf8String(_), Amf0Value::Number(_), Amf0Value::Object(properties), info] => {
+            (properties, info)
+        }
+        _ => return Err("Unexpected connect response".to_string()),
+    };
+
+    let serialize = |values: &[Amf0Value]| {
+        rml_amf0::serialize(&values.to_vec()).map_err(|error| format!("{:?}", error))
+    };
+    let mut data = serialize(&values[..2])?;
+    data.push(AMF0_OBJECT_MARKER);
+    for (name, value) in properties {
+        data.extend_from_slice(&amf0_key(name));
+        data.extend_from_slice(&serialize(std::slice::from_ref(value))?);
+    }
+    let fourccs: Vec<_> = flv::ENHANCED_VIDEO_CODECS
+        .iter()
+        .filter_map(|codec| codec.fourcc())
+        .collect();
+    data.extend_from_slice(&amf0_key("fourCcList"));
+    data.extend_from_slice(&amf0_strict_array(&fourccs));
+    data.extend_from_slice(&AMF0_OBJECT_END);
+    data.extend_from_slice(&serialize(std::slice::from_ref(info))?);
+
+    let mut header = bytes[..header_length].to_vec();
+    header[4..7].copy_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+    append_chunks(
+        &mut header,
+        bytes[0] & CHUNK_STREAM_ID_MASK,
+        extended_timestamp,
+        &data,
+    );
+
+    Ok(Packet {
+        bytes: header,
+        can_be_dropped: packet.can_be_dropped,
+    })
+}
+
+/// Deserializes the messages of the input on the side of the session, keeping up with the chunk
+/// size changes of the peer
+fn read_messages(reader: &mut ChunkDeserializer, bytes: &[u8]) -> Result<Vec<ReadMessage>, String> {
+    let mut messages = Vec::new();
+    let mut bytes = bytes;
+    while let Some(payload) = reader
+        .get_next_message(bytes)
+        .map_err(|error| format!("{:?}", error))?
+    {
+        bytes = &[];
+        match payload.to_rtmp_message() {
+            Ok(RtmpMessage::SetChunkSize { size }) => reader
+                .set_max_chunk_size(size as usize)
+                .map_err(|error| format!("{:?}", error))?,
+            Ok(RtmpMessage::Amf0Data { values }) => messages.extend(
+                DataMessage::new(values, payload.data.clone(), payload.timestamp.value)
+                    .map(ReadMessage::Data),
+            ),
+            Ok(RtmpMessage::Amf0Command {
+                command_name,
+                transaction_id,
+                command_object: Amf0Value::Object(properties),
+                ..
+            }) if command_name == "connect" => messages.push(ReadMessage::Connect {
+                transaction_id,
+                tc_url: string_property(&properties, "tcUrl"),
+                flash_version: string_property(&properties, "flashVer"),
+            }),
+            Ok(RtmpMessage::Amf0Command { command_name, .. }) if command_name == "publish" => {
+                messages.push(ReadMessage::Publish {
+                    stream_id: payload.message_stream_id,
+                })
+            }
+            _ => (),
+        }
+    }
+
+    Ok(messages)
+}
+
+fn string_property(properties: &HashMap<String, Amf0Value>, name: &str) -> Option<String> {
+    match properties.get(name) {
+        Some(Amf0Value::Utf8String(value)) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+/// AMF0 strict array of strings, which the serializer does not support
+fn amf0_strict_array(values: &[&str]) -> Vec<u8> {
+    let mut bytes = vec![AMF0_STRICT_ARRAY_MARKER];
+    bytes.extend_from_slice(&(values.len() as u32).to_be_bytes());
+    for value in values {
+        bytes.push(AMF0_STRING_MARKER);
+        bytes.extend_from_slice(&amf0_key(value));
+    }
+    bytes
+}
+
+/// Length prefixed UTF-8 string, as used by the property names and the short strings of AMF0
+fn amf0_key(value: &str) -> Vec<u8> {
+    let mut bytes = (value.len() as u16).to_be_bytes().to_vec();
+    bytes.extend_from_slice(value.as_bytes());
+    bytes
+}
+
 fn is_video_sequence_header(data: Bytes) -> bool {
-    // This is assuming h264.
-    return data.len() >= 2 && data[0] == 0x17 && data[1] == 0x00;
+    match VideoPacket::parse(&data) {
+        Some(packet) => packet.packet_type == VideoPacketType::SequenceHeader,
+        None => false,
+    }
 }
 
 fn is_audio_sequence_header(data: Bytes) -> bool {
-    // This is assuming aac
-    return data.len() >= 2 && data[0] == 0xaf && data[1] == 0x00;
+    match AudioPacket::parse(&data) {
+        Some(packet) => {
+            packet.packet_type == AudioPacketType::SequenceHeader
+                || packet.packet_type == AudioPacketType::MultichannelConfig
+        }
+        None => false,
+    }
 }
 
 fn is_video_keyframe(data: Bytes) -> bool {
-    // assumings h264
-    return data.len() >= 2 && data[0] == 0x17 && data[1] != 0x00; // 0x00 is the sequence header, don't count that for now
+    // The sequence header is not counted as a keyframe
+    match VideoPacket::parse(&data) {
+        Some(packet) => packet.packet_type == VideoPacketType::CodedFrames && packet.is_keyframe,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueueError;
+    use rml_rtmp::chunk_io::ChunkSerializer;
+    use std::time::Instant;
+
+    const CONNECTION_ID: usize = 0;
+    // HMAC-SHA256 of "live/somekey/4102444800" (2100-01-01) with "secret"
+    const TOKEN: &str = "ae2263e6bc88364825dac7f5d3ea7c82e6e1ab21bd85930f99c756c98379f962";
+
+    struct Listener;
+
+    impl ServerListener for Listener {
+        fn client_connected(&self, _connection_id: usize, _address: &str) {}
+        fn connect_requested(
+            &self,
+            _connection_id: usize,
+            _app_name: &str,
+            _tc_url: Option<&str>,
+            _flash_version: Option<&str>,
+        ) {
+        }
+        fn publish_requested(
+            &self,
+            _connection_id: usize,
+            _app_name: &str,
+            _stream_key: &str,
+            _mode: &str,
+        ) -> bool {
+            true
+        }
+        fn publish_ended(&self, _app_name: &str, _stream_key: &str) {}
+        fn client_disconnected(&self, _connection_id: usize) {}
+        fn handshake_timed_out(&self, _connection_id: usize) {}
+    }
+
+    /// Client side of a connection, sending the messages of a publisher and reading the responses
+    /// of the server
+    struct Peer {
+        serializer: ChunkSerializer,
+        deserializer: ChunkDeserializer,
+    }
+
+    impl Peer {
+        fn new() -> Self {
+            Peer {
+                serializer: ChunkSerializer::new(),
+                deserializer: ChunkDeserializer::new(),
+            }
+        }
+
+        fn send(
+            &mut self,
+            server: &mut Server,
+            message: RtmpMessage,
+            stream_id: u32,
+        ) -> Vec<MessagePayload> {
+            let payload = message
+                .into_message_payload(RtmpTimestamp::new(0), stream_id)
+                .unwrap();
+            let packet = self.serializer.serialize(&payload, false, false).unwrap();
+
+            let mut payloads = Vec::new();
+            for result in server.bytes_received(CONNECTION_ID, &packet.bytes).unwrap() {
+                if let ServerResult::OutboundPacket { packet, .. } = result {
+                    let mut bytes = &packet.bytes[..];
+                    while let Some(payload) = self.deserializer.get_next_message(bytes).unwrap() {
+                        bytes = &[];
+                        if let Ok(RtmpMessage::SetChunkSize { size }) = payload.to_rtmp_message() {
+                            self.deserializer.set_max_chunk_size(size as usize).unwrap();
+                        }
+                        payloads.push(payload);
+                    }
+                }
+            }
+            payloads
+        }
+
+        fn command(
+            &mut self,
+            server: &mut Server,
+            command_name: &str,
+            transaction_id: f64,
+            command_object: Amf0Value,
+            additional_arguments: Vec<Amf0Value>,
+            stream_id: u32,
+        ) -> Vec<MessagePayload> {
+            let message = RtmpMessage::Amf0Command {
+                command_name: command_name.to_string(),
+                transaction_id,
+                command_object,
+                additional_arguments,
+            };
+            self.send(server, message, stream_id)
+        }
+
+        fn connect(&mut self, server: &mut Server, app_name: &str) -> Vec<MessagePayload> {
+            let mut properties = HashMap::new();
+            properties.insert(
+                "app".to_string(),
+                Amf0Value::Utf8String(app_name.to_string()),
+            );
+            self.command(
+                server,
+                "connect",
+                1.0,
+                Amf0Value::Object(properties),
+                Vec::new(),
+                0,
+            )
+        }
+
+        /// Creates the first stream of the connection and publishes the stream key on it
+        fn publish(&mut self, server: &mut Server, stream_key: &str) -> Vec<MessagePayload> {
+            self.command(server, "createStream", 2.0, Amf0Value::Null, Vec::new(), 0);
+            let arguments = vec![
+                Amf0Value::Utf8String(stream_key.to_string()),
+                Amf0Value::Utf8String("live".to_string()),
+            ];
+            self.command(server, "publish", 3.0, Amf0Value::Null, arguments, 1)
+        }
+    }
+
+    fn server(config: ServerConfig) -> (Server, Arc<MediaQueue>) {
+        let queue = Arc::new(MediaQueue::new());
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.insert(Subscription {
+            config,
+            queue: queue.clone(),
+            listener: Arc::new(Listener),
+        });
+        let server = Server::new(
+            Arc::new(Mutex::new(subscriptions)),
+            Arc::new(Mutex::new(ServerStats::default())),
+        );
+        (server, queue)
+    }
+
+    fn received(queue: &MediaQueue) -> Vec<RtmpInput> {
+        let mut inputs = Vec::new();
+        loop {
+            match queue.pop(Some(Instant::now())) {
+                Ok(input) => inputs.push(input),
+                Err(QueueError::Timeout) => return inputs,
+                Err(error) => panic!("Unexpected queue error {:?}", error),
+            }
+        }
+    }
+
+    /// Codes of the status objects of the commands
+    fn status_codes(payloads: &[MessagePayload]) -> Vec<String> {
+        payloads
+            .iter()
+            .filter_map(|payload| match payload.to_rtmp_message() {
+                Ok(RtmpMessage::Amf0Command {
+                    additional_arguments,
+                    ..
+                }) => match additional_arguments.into_iter().next() {
+                    Some(Amf0Value::Object(properties)) => string_property(&properties, "code"),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn signed_config() -> ServerConfig {
+        ServerConfig {
+            secret: Some("secret".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn delivers_the_media_of_signed_stream_keys() {
+        let (mut server, queue) = server(signed_config());
+        let mut peer = Peer::new();
+        peer.connect(&mut server, "live");
+        let stream_key = format!("somekey?token={}&expires=4102444800", TOKEN);
+        let payloads = peer.publish(&mut server, &stream_key);
+        assert!(status_codes(&payloads).contains(&"NetStream.Publish.Start".to_string()));
+
+        let mut properties = HashMap::new();
+        properties.insert("width".to_string(), Amf0Value::Number(1280.0));
+        let values = vec![
+            Amf0Value::Utf8String("@setDataFrame".to_string()),
+            Amf0Value::Utf8String("onMetaData".to_string()),
+            Amf0Value::Object(properties),
+        ];
+        peer.send(&mut server, RtmpMessage::Amf0Data { values }, 1);
+        for data in [
+            flv::avc_sequence_header(&[1, 0x64]),
+            flv::avc_nalu(&[0x65], true, 0),
+        ] {
+            let message = RtmpMessage::VideoData {
+                data: Bytes::from(data),
+            };
+            peer.send(&mut server, message, 1);
+        }
+
+        let channel = ChannelKey {
+            app_name: "live".to_string(),
+            stream_key: "somekey".to_string(),
+        };
+        let inputs = received(&queue);
+        assert!(inputs.iter().any(|input| match input {
+            RtmpInput::PublishStarted(key) => *key == channel,
+            _ => false,
+        }));
+        assert!(inputs.iter().any(|input| match input {
+            RtmpInput::Metadata(key, metadata) => {
+                *key == channel && metadata.video_width == Some(1280)
+            }
+            _ => false,
+        }));
+        let media = inputs
+            .iter()
+            .filter(|input| match input {
+                RtmpInput::Media(key, _) => *key == channel,
+                _ => false,
+            })
+            .count();
+        assert_eq!(media, 2);
+    }
+
+    #[test]
+    fn rejects_invalid_signatures() {
+        let (mut server, queue) = server(signed_config());
+        let mut peer = Peer::new();
+        peer.connect(&mut server, "live");
+        let payloads = peer.publish(&mut server, "somekey?token=00&expires=4102444800");
+
+        assert_eq!(status_codes(&payloads), vec!["NetStream.Publish.BadName"]);
+        assert!(received(&queue).is_empty());
+    }
+
+    #[test]
+    fn lists_the_fourccs_in_the_connect_response() {
+        let (mut server, _queue) = server(ServerConfig::default());
+        let mut peer = Peer::new();
+        let payloads = peer.connect(&mut server, "live");
+
+        let command_name = rml_amf0::serialize(&vec![Amf0Value::Utf8String("_result".into())]);
+        let response = payloads
+            .iter()
+            .find(|payload| payload.data.starts_with(command_name.as_ref().unwrap()))
+            .expect("No connect response");
+        let mut fourcc_list = amf0_key("fourCcList");
+        fourcc_list.extend_from_slice(&amf0_strict_array(&["avc1", "hvc1", "av01", "vp09"]));
+        let contains = |bytes: &[u8]| response.data.windows(bytes.len()).any(|w| w == bytes);
+        assert!(contains(&fourcc_list));
+        assert!(contains(b"fmsVer"));
+        assert!(contains(b"NetConnection.Connect.Success"));
+
+        // The following responses of the chunk stream can still be read
+        let payloads = peer.command(&mut server, "createStream", 2.0, Amf0Value::Null, vec![], 0);
+        let stream_ids: Vec<_> = payloads
+            .iter()
+            .filter_map(|payload| match payload.to_rtmp_message() {
+                Ok(RtmpMessage::Amf0Command {
+                    additional_arguments,
+                    ..
+                }) => additional_arguments.into_iter().next(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(stream_ids, vec![Amf0Value::Number(1.0)]);
+    }
 }
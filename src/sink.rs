@@ -0,0 +1,703 @@
+use crate::data::{ChannelKey, Media, MediaType, RtmpInput};
+use crate::flv;
+use crate::registry;
+use crate::registry::{SharedServer, CONTEXT_TYPE};
+use crate::tls;
+use bytes::Bytes;
+use glib::subclass;
+use glib::subclass::prelude::*;
+use gst::prelude::*;
+use gst::subclass::prelude::*;
+use gst::{gst_debug, gst_info, gst_warning};
+use once_cell::sync::Lazy;
+use rml_rtmp::sessions::StreamMetadata;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::u32;
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "rtmpsrvsink",
+        gst::DebugColorFlags::empty(),
+        Some("RTMP Server Sink"),
+    )
+});
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0";
+const DEFAULT_PORT: u32 = 5000;
+const DEFAULT_APP: &str = "live";
+const DEFAULT_STREAM_KEY: &str = "stream";
+
+// Codec ids advertised in the metadata, as in the video and audio tag headers
+const VIDEO_CODEC_ID_AVC: &str = "7";
+const AUDIO_CODEC_ID_AAC: &str = "10";
+
+#[derive(Debug, Clone)]
+struct Settings {
+    address: String,
+    port: u32,
+    app_name: String,
+    stream_key: String,
+    tls_certificate: Option<String>,
+    tls_key: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            address: DEFAULT_ADDRESS.into(),
+            port: DEFAULT_PORT,
+            app_name: DEFAULT_APP.into(),
+            stream_key: DEFAULT_STREAM_KEY.into(),
+            tls_certificate: None,
+            tls_key: None,
+        }
+    }
+}
+
+static PROPERTIES: [subclass::Property; 6] = [
+    subclass::Property("address", |name| {
+        glib::ParamSpec::string(
+            name,
+            "Address",
+            "The address the server should listen for incoming connections",
+            DEFAULT_ADDRESS.into(),
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("port", |name| {
+        glib::ParamSpec::uint(
+            name,
+            "Port",
+            "The port that the server should bind to",
+            1000,
+            u32::MAX,
+            DEFAULT_PORT,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("app", |name| {
+        glib::ParamSpec::string(
+            name,
+            "Application",
+            "The RTMP application name players connect to",
+            DEFAULT_APP.into(),
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("stream_key", |name| {
+        glib::ParamSpec::string(
+            name,
+            "Stream Key",
+            "The stream key players request to watch the content",
+            DEFAULT_STREAM_KEY.into(),
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("tls_certificate", |name| {
+        glib::ParamSpec::string(
+            name,
+            "TLS Certificate",
+            "PEM file with the certificate chain of the server, players connect with RTMPS when set",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("tls_key", |name| {
+        glib::ParamSpec::string(
+            name,
+            "TLS Key",
+            "PEM file with the private key of the TLS certificate",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PadKind {
+    /// FLV byte stream carrying both tracks
+    Flv,
+    Video,
+    Audio,
+}
+
+struct SinkPad {
+    kind: PadKind,
+    segment: gst::FormattedSegment<gst::ClockTime>,
+    tag_reader: flv::TagReader,
+    is_eos: bool,
+}
+
+impl SinkPad {
+    fn new(kind: PadKind) -> SinkPad {
+        SinkPad {
+            kind,
+            segment: gst::FormattedSegment::new(),
+            tag_reader: flv::TagReader::new(),
+            is_eos: false,
+        }
+    }
+}
+
+struct Started {
+    server: Arc<SharedServer>,
+    channel: ChannelKey,
+    metadata: StreamMetadata,
+    /// Running time of the first buffer, the RTMP timestamps are relative to it
+    base_running_time: Option<u64>,
+    last_timestamp: u32,
+}
+
+impl Started {
+    /// Maps running time (in nanoseconds) to a RTMP timestamp (in milliseconds), buffers without
+    /// timestamps take the one of the previous buffer
+    fn timestamp(&mut self, running_time: Option<u64>) -> u32 {
+        if let Some(running_time) = running_time {
+            let base_running_time = *self.base_running_time.get_or_insert(running_time);
+            self.last_timestamp =
+                (running_time.saturating_sub(base_running_time) / 1_000_000) as u32;
+        }
+        self.last_timestamp
+    }
+
+    fn publish_media(&self, media_type: MediaType, data: Vec<u8>, timestamp: u32) {
+        self.server.publish(RtmpInput::Media(
+            self.channel.clone(),
+            Media {
+                media_type,
+                data: Bytes::from(data),
+                timestamp,
+                can_be_dropped: true,
+            },
+        ));
+    }
+
+    fn publish_metadata(&self) {
+        self.server.publish(RtmpInput::Metadata(
+            self.channel.clone(),
+            self.metadata.clone(),
+        ));
+    }
+}
+
+pub struct RtmpSvrSink {
+    settings: Mutex<Settings>,
+    /// Locked before the state when both are needed
+    pads: Mutex<HashMap<gst::Pad, SinkPad>>,
+    state: Mutex<Option<Started>>,
+    /// Context set by the pipeline, pointing to a server started by another element
+    context: Mutex<Option<gst::Context>>,
+}
+
+impl ObjectSubclass for RtmpSvrSink {
+    const NAME: &'static str = "RtmpSvrSink";
+    type Type = super::RtmpSrvSink;
+    type ParentType = gst::Element;
+    type Instance = gst::subclass::ElementInstanceStruct<Self>;
+    type Class = subclass::simple::ClassStruct<Self>;
+
+    glib::object_subclass!();
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.set_metadata(
+            "RTMP Server Sink",
+            "Sink/Network",
+            "Creates a server RTMP players can watch the content of the pipeline from",
+            "Rafael Caricio <rafael@caricio.com>",
+        );
+
+        let caps = gst::Caps::new_simple("video/x-flv", &[]);
+        let flv_pad_template = gst::PadTemplate::new(
+            "flv",
+            gst::PadDirection::Sink,
+            gst::PadPresence::Request,
+            &caps,
+        )
+        .unwrap();
+        klass.add_pad_template(flv_pad_template);
+
+        let caps = gst::Caps::builder("video/x-h264")
+            .field("stream-format", &"avc")
+            .field("alignment", &"au")
+            .build();
+        let video_pad_template = gst::PadTemplate::new(
+            "video",
+            gst::PadDirection::Sink,
+            gst::PadPresence::Request,
+            &caps,
+        )
+        .unwrap();
+        klass.add_pad_template(video_pad_template);
+
+        let caps = gst::Caps::builder("audio/mpeg")
+            .field("mpegversion", &4i32)
+            .field("stream-format", &"raw")
+            .build();
+        let audio_pad_template = gst::PadTemplate::new(
+            "audio",
+            gst::PadDirection::Sink,
+            gst::PadPresence::Request,
+            &caps,
+        )
+        .unwrap();
+        klass.add_pad_template(audio_pad_template);
+
+        klass.install_properties(&PROPERTIES);
+    }
+
+    fn new() -> Self {
+        Self {
+            settings: Mutex::new(Default::default()),
+            pads: Mutex::new(HashMap::new()),
+            state: Mutex::new(None),
+            context: Mutex::new(None),
+        }
+    }
+}
+
+impl ObjectImpl for RtmpSvrSink {
+    fn set_property(&self, obj: &Self::Type, id: usize, value: &glib::Value) {
+        let prop = &PROPERTIES[id];
+        match *prop {
+            subclass::Property("address", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let address = value
+                    .get()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_ADDRESS)
+                    .into();
+                settings.address = address;
+                gst_debug!(CAT, obj: obj, "Set address to: {}", settings.address);
+            }
+            subclass::Property("port", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let port = value.get_some().expect("type checked upstream");
+                settings.port = port;
+                gst_debug!(CAT, obj: obj, "Set port to: {}", port);
+            }
+            subclass::Property("app", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let app_name = value
+                    .get()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_APP)
+                    .into();
+                settings.app_name = app_name;
+                gst_debug!(CAT, obj: obj, "Set application to: {}", settings.app_name);
+            }
+            subclass::Property("stream_key", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let stream_key = value
+                    .get()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_STREAM_KEY)
+                    .into();
+                settings.stream_key = stream_key;
+                gst_debug!(CAT, obj: obj, "Set stream key to: {}", settings.stream_key);
+            }
+            subclass::Property("tls_certificate", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tls_certificate = value.get().expect("type checked upstream");
+                gst_debug!(
+                    CAT,
+                    obj: obj,
+                    "Set TLS certificate to: {:?}",
+                    settings.tls_certificate
+                );
+            }
+            subclass::Property("tls_key", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tls_key = value.get().expect("type checked upstream");
+                gst_debug!(CAT, obj: obj, "Set TLS key to: {:?}", settings.tls_key);
+            }
+            _ => unimplemented!(),
+        };
+    }
+
+    fn get_property(&self, _obj: &Self::Type, id: usize) -> glib::Value {
+        let prop = &PROPERTIES[id];
+        match *prop {
+            subclass::Property("address", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.address.to_value()
+            }
+            subclass::Property("port", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.port.to_value()
+            }
+            subclass::Property("app", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.app_name.to_value()
+            }
+            subclass::Property("stream_key", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.stream_key.to_value()
+            }
+            subclass::Property("tls_certificate", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.tls_certificate.to_value()
+            }
+            subclass::Property("tls_key", ..) => {
+                let settings = self.settings.lock().unwrap();
+                settings.tls_key.to_value()
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn constructed(&self, obj: &Self::Type) {
+        self.parent_constructed(obj);
+        obj.set_element_flags(gst::ElementFlags::SINK);
+    }
+}
+
+impl ElementImpl for RtmpSvrSink {
+    fn set_context(&self, element: &Self::Type, context: &gst::Context) {
+        if context.get_context_type() == CONTEXT_TYPE {
+            gst_debug!(CAT, obj: element, "Received server context");
+            *self.context.lock().unwrap() = Some(context.clone());
+        }
+        self.parent_set_context(element, context)
+    }
+
+    fn change_state(
+        &self,
+        element: &Self::Type,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        if transition == gst::StateChange::ReadyToPaused {
+            self.start(element).map_err(|err| {
+                element.post_error_message(err);
+                gst::StateChangeError
+            })?;
+        }
+
+        let success = self.parent_change_state(element, transition)?;
+
+        if transition == gst::StateChange::PausedToReady {
+            self.stop(element);
+        }
+
+        Ok(success)
+    }
+
+    fn request_new_pad(
+        &self,
+        element: &Self::Type,
+        templ: &gst::PadTemplate,
+        _name: Option<String>,
+        _caps: Option<&gst::Caps>,
+    ) -> Option<gst::Pad> {
+        let name = templ.get_name_template();
+        let kind = match name.as_str() {
+            "flv" => PadKind::Flv,
+            "video" => PadKind::Video,
+            "audio" => PadKind::Audio,
+            _ => return None,
+        };
+
+        let mut pads = self.pads.lock().unwrap();
+        // The FLV stream already carries both tracks, and there is only one track of each type
+        let is_taken = pads
+            .values()
+            .any(|pad| pad.kind == kind || pad.kind == PadKind::Flv || kind == PadKind::Flv);
+        if is_taken {
+            gst_warning!(CAT, obj: element, "Cannot request a {} pad now", name);
+            return None;
+        }
+
+        let pad = gst::Pad::builder_with_template(templ, Some(name.as_str()))
+            .chain_function(|pad, parent, buffer| {
+                RtmpSvrSink::catch_panic_pad_function(
+                    parent,
+                    || Err(gst::FlowError::Error),
+                    |sink, element| sink.sink_chain(pad, element, buffer),
+                )
+            })
+            .event_function(|pad, parent, event| {
+                RtmpSvrSink::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |sink, element| sink.sink_event(pad, element, event),
+                )
+            })
+            .build();
+
+        pads.insert(pad.clone(), SinkPad::new(kind));
+        drop(pads);
+
+        if element.get_current_state() > gst::State::Ready {
+            pad.set_active(true).ok()?;
+        }
+        element.add_pad(&pad).ok()?;
+
+        Some(pad)
+    }
+
+    fn release_pad(&self, element: &Self::Type, pad: &gst::Pad) {
+        self.pads.lock().unwrap().remove(pad);
+        let _ = pad.set_active(false);
+        let _ = element.remove_pad(pad);
+    }
+}
+
+impl RtmpSvrSink {
+    fn start(&self, element: &super::RtmpSrvSink) -> Result<(), gst::ErrorMessage> {
+        let settings = self.settings.lock().unwrap().clone();
+        let tls = tls::optional_server_config(
+            settings.tls_certificate.as_deref(),
+            settings.tls_key.as_deref(),
+        )
+        .map_err(|err| gst::error_msg!(gst::ResourceError::Settings, ["{}", err]))?;
+        let address = format!("{}:{}", settings.address, settings.port);
+        let server = registry::acquire_shared(element.upcast_ref(), &self.context, &address, tls)
+            .map_err(|err| {
+            gst::error_msg!(
+                gst::ResourceError::Busy,
+                ["Failed to bind to address {}: {}", address, err]
+            )
+        })?;
+
+        let channel = ChannelKey {
+            app_name: settings.app_name,
+            stream_key: settings.stream_key,
+        };
+        gst_info!(
+            CAT,
+            obj: element,
+            "Serving rtmp://{}/{}",
+            server.address(),
+            channel
+        );
+        server.publish(RtmpInput::PublishStarted(channel.clone()));
+
+        *self.state.lock().unwrap() = Some(Started {
+            server,
+            channel,
+            metadata: flv::empty_metadata(),
+            base_running_time: None,
+            last_timestamp: 0,
+        });
+
+        Ok(())
+    }
+
+    fn stop(&self, element: &super::RtmpSrvSink) {
+        let mut pads = self.pads.lock().unwrap();
+        for pad in pads.values_mut() {
+            *pad = SinkPad::new(pad.kind);
+        }
+
+        if let Some(started) = self.state.lock().unwrap().take() {
+            started
+                .server
+                .publish(RtmpInput::PublishEnded(started.channel.clone()));
+            SharedServer::release(started.server);
+            gst_debug!(CAT, obj: element, "Server released");
+        }
+    }
+
+    fn sink_chain(
+        &self,
+        pad: &gst::Pad,
+        element: &super::RtmpSrvSink,
+        buffer: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let mut pads = self.pads.lock().unwrap();
+        let sink_pad = pads.get_mut(pad).ok_or(gst::FlowError::Error)?;
+        let mut state = self.state.lock().unwrap();
+        let started = state.as_mut().ok_or(gst::FlowError::Flushing)?;
+
+        let map = buffer.map_readable().map_err(|_| {
+            gst::element_error!(element, gst::CoreError::Failed, ["Failed to map buffer"]);
+            gst::FlowError::Error
+        })?;
+
+        match sink_pad.kind {
+            PadKind::Flv => {
+                sink_pad.tag_reader.push(&map);
+                loop {
+                    let tag = match sink_pad.tag_reader.next_tag() {
+                        Ok(Some(tag)) => tag,
+                        Ok(None) => break,
+                        Err(error) => {
+                            gst::element_error!(
+                                element,
+                                gst::StreamError::Demux,
+                                ["Invalid FLV stream: {}", error]
+                            );
+                            return Err(gst::FlowError::Error);
+                        }
+                    };
+
+                    match tag.tag_type {
+                        flv::TAG_TYPE_VIDEO => started.publish_media(
+                            MediaType::Video,
+                            tag.body.to_vec(),
+                            tag.timestamp,
+                        ),
+                        flv::TAG_TYPE_AUDIO => started.publish_media(
+                            MediaType::Audio,
+                            tag.body.to_vec(),
+                            tag.timestamp,
+                        ),
+                        flv::TAG_TYPE_SCRIPT_DATA => {
+                            if let Some(metadata) = flv::parse_metadata_tag_body(&tag.body) {
+                                started.metadata = metadata;
+                                started.publish_metadata();
+                            }
+                        }
+                        tag_type => {
+                            gst_debug!(CAT, obj: element, "Skipping tag of type {}", tag_type)
+                        }
+                    }
+                }
+            }
+
+            PadKind::Video => {
+                let dts = buffer.get_dts().nseconds().or(buffer.get_pts().nseconds());
+                let timestamp = started.timestamp(running_time(&sink_pad.segment, dts));
+                let composition_time = match (buffer.get_pts().nseconds(), dts) {
+                    (Some(pts), Some(dts)) => ((pts as i64 - dts as i64) / 1_000_000) as i32,
+                    _ => 0,
+                };
+                let is_keyframe = !buffer.get_flags().contains(gst::BufferFlags::DELTA_UNIT);
+                started.publish_media(
+                    MediaType::Video,
+                    flv::avc_nalu(&map, is_keyframe, composition_time),
+                    timestamp,
+                );
+            }
+
+            PadKind::Audio => {
+                let pts = buffer.get_pts().nseconds();
+                let timestamp = started.timestamp(running_time(&sink_pad.segment, pts));
+                started.publish_media(MediaType::Audio, flv::aac_raw(&map), timestamp);
+            }
+        }
+
+        Ok(gst::FlowSuccess::Ok)
+    }
+
+    fn sink_event(&self, pad: &gst::Pad, element: &super::RtmpSrvSink, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        gst_debug!(CAT, obj: pad, "Handling event {:?}", event);
+        match event.view() {
+            EventView::Caps(ev) => {
+                let caps = ev.get_caps_owned();
+                self.set_caps(pad, element, &caps);
+                true
+            }
+            EventView::Segment(ev) => {
+                let mut pads = self.pads.lock().unwrap();
+                if let Some(sink_pad) = pads.get_mut(pad) {
+                    // FLV streams carry their own timestamps, in a byte segment
+                    if let Some(segment) = ev.get_segment().downcast_ref::<gst::ClockTime>() {
+                        sink_pad.segment = segment.clone();
+                    }
+                }
+                true
+            }
+            EventView::FlushStop(..) => {
+                let mut pads = self.pads.lock().unwrap();
+                if let Some(sink_pad) = pads.get_mut(pad) {
+                    *sink_pad = SinkPad::new(sink_pad.kind);
+                }
+                true
+            }
+            EventView::Eos(..) => {
+                let mut pads = self.pads.lock().unwrap();
+                if let Some(sink_pad) = pads.get_mut(pad) {
+                    sink_pad.is_eos = true;
+                }
+
+                if pads.values().all(|pad| pad.is_eos) {
+                    drop(pads);
+                    gst_info!(CAT, obj: element, "All pads are EOS");
+                    let _ = element
+                        .post_message(gst::message::Eos::builder().src(Some(element)).build());
+                }
+                true
+            }
+            _ => pad.event_default(Some(element), event),
+        }
+    }
+
+    /// Publishes the sequence header carried in the caps and updates the stream metadata
+    fn set_caps(&self, pad: &gst::Pad, element: &super::RtmpSrvSink, caps: &gst::Caps) {
+        let pads = self.pads.lock().unwrap();
+        let kind = match pads.get(pad) {
+            Some(sink_pad) => sink_pad.kind,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        let started = match state.as_mut() {
+            Some(started) => started,
+            None => return,
+        };
+        let s = match caps.get_structure(0) {
+            Some(s) => s,
+            None => return,
+        };
+
+        gst_debug!(CAT, obj: element, "Received caps {}", caps);
+        let codec_data = s.get::<gst::Buffer>("codec_data").ok().flatten();
+        let timestamp = started.last_timestamp;
+        match kind {
+            PadKind::Flv => return,
+            PadKind::Video => {
+                let metadata = &mut started.metadata;
+                metadata.video_codec = Some(VIDEO_CODEC_ID_AVC.to_string());
+                metadata.video_width = s.get_some::<i32>("width").ok().map(|val| val as u32);
+                metadata.video_height = s.get_some::<i32>("height").ok().map(|val| val as u32);
+                metadata.video_frame_rate = s
+                    .get_some::<gst::Fraction>("framerate")
+                    .ok()
+                    .filter(|framerate| *framerate.denom() != 0)
+                    .map(|framerate| *framerate.numer() as f32 / *framerate.denom() as f32);
+
+                if let Some(map) = codec_data
+                    .as_ref()
+                    .and_then(|data| data.map_readable().ok())
+                {
+                    started.publish_media(
+                        MediaType::Video,
+                        flv::avc_sequence_header(&map),
+                        timestamp,
+                    );
+                }
+            }
+            PadKind::Audio => {
+                let metadata = &mut started.metadata;
+                metadata.audio_codec = Some(AUDIO_CODEC_ID_AAC.to_string());
+                metadata.audio_sample_rate = s.get_some::<i32>("rate").ok().map(|val| val as u32);
+                metadata.audio_channels = s.get_some::<i32>("channels").ok().map(|val| val as u32);
+                metadata.audio_is_stereo = metadata.audio_channels.map(|channels| channels == 2);
+
+                if let Some(map) = codec_data
+                    .as_ref()
+                    .and_then(|data| data.map_readable().ok())
+                {
+                    started.publish_media(
+                        MediaType::Audio,
+                        flv::aac_sequence_header(&map),
+                        timestamp,
+                    );
+                }
+            }
+        }
+
+        started.publish_metadata();
+    }
+}
+
+fn running_time(
+    segment: &gst::FormattedSegment<gst::ClockTime>,
+    timestamp: Option<u64>,
+) -> Option<u64> {
+    segment
+        .to_running_time(gst::ClockTime::from_nseconds(timestamp?))
+        .nseconds()
+}
@@ -0,0 +1,166 @@
+use crate::data::{ChannelKey, MediaType};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+const BITRATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Publisher,
+    Watcher,
+}
+
+impl Role {
+    pub fn nick(&self) -> &'static str {
+        match self {
+            Role::Publisher => "publisher",
+            Role::Watcher => "watcher",
+        }
+    }
+}
+
+/// Counters of a connection, the media ones count what a publisher sent or what a watcher received
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    pub peer_address: String,
+    pub connected_since: SystemTime,
+    pub channel: Option<ChannelKey>,
+    pub role: Option<Role>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub video_tags: u64,
+    pub audio_tags: u64,
+    /// Bits per second of the media over the last second
+    pub bitrate: u64,
+    /// Milliseconds between the last two keyframes
+    pub keyframe_interval: Option<u32>,
+    pub last_timestamp: Option<u32>,
+    /// Media not sent to a watcher while waiting for a keyframe
+    pub dropped_frames: u64,
+    last_keyframe: Option<u32>,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl ConnectionStats {
+    fn new(peer_address: &str) -> ConnectionStats {
+        ConnectionStats {
+            peer_address: peer_address.to_string(),
+            connected_since: SystemTime::now(),
+            channel: None,
+            role: None,
+            bytes_in: 0,
+            bytes_out: 0,
+            video_tags: 0,
+            audio_tags: 0,
+            bitrate: 0,
+            keyframe_interval: None,
+            last_timestamp: None,
+            dropped_frames: 0,
+            last_keyframe: None,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    pub fn media(&mut self, media_type: MediaType, size: usize, timestamp: u32, is_keyframe: bool) {
+        match media_type {
+            MediaType::Video => self.video_tags += 1,
+            MediaType::Audio => self.audio_tags += 1,
+        }
+        self.last_timestamp = Some(timestamp);
+
+        if is_keyframe {
+            if let Some(last_keyframe) = self.last_keyframe {
+                self.keyframe_interval = Some(timestamp.wrapping_sub(last_keyframe));
+            }
+            self.last_keyframe = Some(timestamp);
+        }
+
+        self.window_bytes += size as u64;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= BITRATE_WINDOW {
+            self.bitrate = self.window_bytes * 8 * 1000 / elapsed.as_millis() as u64;
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+    }
+}
+
+/// Reads the counters of a server, without keeping the server running like its other handles do
+#[derive(Clone)]
+pub struct StatsHandle {
+    address: String,
+    stats: Arc<Mutex<ServerStats>>,
+}
+
+impl StatsHandle {
+    pub fn new(address: &str, stats: Arc<Mutex<ServerStats>>) -> StatsHandle {
+        StatsHandle {
+            address: address.to_string(),
+            stats,
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Snapshot of the counters of the server and its connections
+    pub fn snapshot(&self) -> ServerStats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+/// Counters of a server, shared by the elements using it
+#[derive(Debug, Clone, Default)]
+pub struct ServerStats {
+    pub total_connections: u64,
+    pub connections: BTreeMap<usize, ConnectionStats>,
+}
+
+impl ServerStats {
+    pub fn connection_opened(&mut self, connection_id: usize, peer_address: &str) {
+        self.total_connections += 1;
+        self.connections
+            .insert(connection_id, ConnectionStats::new(peer_address));
+    }
+
+    pub fn connection_closed(&mut self, connection_id: usize) {
+        self.connections.remove(&connection_id);
+    }
+
+    pub fn bytes_received(&mut self, connection_id: usize, count: usize) {
+        if let Some(connection) = self.connections.get_mut(&connection_id) {
+            connection.bytes_in += count as u64;
+        }
+    }
+
+    pub fn bytes_sent(&mut self, connection_id: usize, count: usize) {
+        if let Some(connection) = self.connections.get_mut(&connection_id) {
+            connection.bytes_out += count as u64;
+        }
+    }
+
+    pub fn role_changed(&mut self, connection_id: usize, role: Role, channel: &ChannelKey) {
+        if let Some(connection) = self.connections.get_mut(&connection_id) {
+            connection.role = Some(role);
+            connection.channel = Some(channel.clone());
+        }
+    }
+
+    pub fn connection_mut(&mut self, connection_id: usize) -> Option<&mut ConnectionStats> {
+        self.connections.get_mut(&connection_id)
+    }
+
+    pub fn watchers(&self, channel: &ChannelKey) -> u32 {
+        self.connections
+            .values()
+            .filter(|connection| {
+                connection.role == Some(Role::Watcher)
+                    && connection.channel.as_ref() == Some(channel)
+            })
+            .count() as u32
+    }
+}
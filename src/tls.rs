@@ -0,0 +1,150 @@
+use rustls::internal::pemfile;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+/// TLS configuration of the server from the element properties, none when RTMPS is not enabled
+pub fn optional_server_config(
+    certificate_path: Option<&str>,
+    key_path: Option<&str>,
+) -> Result<Option<Arc<rustls::ServerConfig>>, String> {
+    match (certificate_path, key_path) {
+        (None, None) => Ok(None),
+        (Some(certificate_path), Some(key_path)) => {
+            server_config(certificate_path, key_path).map(Some)
+        }
+        _ => Err("Both the TLS certificate and key have to be set".to_string()),
+    }
+}
+
+/// Loads the PEM encoded certificate chain and private key (PKCS#8 or RSA) of a RTMPS server
+pub fn server_config(
+    certificate_path: &str,
+    key_path: &str,
+) -> Result<Arc<rustls::ServerConfig>, String> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|error| format!("Failed to open {}: {}", path, error))
+    };
+
+    let certificates = pemfile::certs(&mut open(certificate_path)?)
+        .map_err(|_| format!("Invalid certificate in {}", certificate_path))?;
+    if certificates.is_empty() {
+        return Err(format!("No certificate found in {}", certificate_path));
+    }
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key_path)?)
+        .map_err(|_| format!("Invalid private key in {}", key_path))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key_path)?)
+            .map_err(|_| format!("Invalid private key in {}", key_path))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("No private key found in {}", key_path))?;
+
+    let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    config
+        .set_single_cert(certificates, key)
+        .map_err(|error| format!("Invalid certificate or key: {}", error))?;
+
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{Connection, ReadResult};
+    use rml_rtmp::handshake::{Handshake, HandshakeProcessResult, PeerType};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // Self-signed certificate of localhost, with its PKCS#8 private key
+    const CERTIFICATE: &str = "tests/data/localhost.crt";
+    const KEY: &str = "tests/data/localhost.key";
+
+    fn test_file(path: &str) -> String {
+        format!("{}/{}", env!("CARGO_MANIFEST_DIR"), path)
+    }
+
+    /// Connects as a RTMPS client trusting the test certificate, completes the RTMP handshake then
+    /// sends the bytes
+    fn publish(address: std::net::SocketAddr, bytes: &[u8]) {
+        let mut config = rustls::ClientConfig::new();
+        let mut certificate = BufReader::new(File::open(test_file(CERTIFICATE)).unwrap());
+        config.root_store.add_pem_file(&mut certificate).unwrap();
+        let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut session = rustls::ClientSession::new(&Arc::new(config), name);
+        let mut socket = TcpStream::connect(address).unwrap();
+        let mut stream = rustls::Stream::new(&mut session, &mut socket);
+
+        let mut handshake = Handshake::new(PeerType::Client);
+        let bytes_0_and_1 = handshake.generate_outbound_p0_and_p1().unwrap();
+        stream.write_all(&bytes_0_and_1).unwrap();
+        let mut buffer = [0; 4096];
+        loop {
+            let byte_count = stream.read(&mut buffer).unwrap();
+            assert!(byte_count > 0, "The server closed the connection");
+            match handshake.process_bytes(&buffer[..byte_count]).unwrap() {
+                HandshakeProcessResult::InProgress { response_bytes } => {
+                    stream.write_all(&response_bytes).unwrap()
+                }
+                HandshakeProcessResult::Completed { response_bytes, .. } => {
+                    stream.write_all(&response_bytes).unwrap();
+                    break;
+                }
+            }
+        }
+        stream.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn is_optional() {
+        assert!(matches!(optional_server_config(None, None), Ok(None)));
+    }
+
+    #[test]
+    fn requires_certificate_and_key() {
+        assert!(optional_server_config(Some("cert.pem"), None).is_err());
+        assert!(optional_server_config(None, Some("key.pem")).is_err());
+    }
+
+    #[test]
+    fn reports_missing_files() {
+        match server_config("/nonexistent/cert.pem", "/nonexistent/key.pem") {
+            Err(error) => assert!(error.starts_with("Failed to open /nonexistent/cert.pem")),
+            Ok(_) => panic!("Missing files were accepted"),
+        }
+    }
+
+    #[test]
+    fn serves_rtmp_over_tls() {
+        let config = server_config(&test_file(CERTIFICATE), &test_file(KEY)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || publish(address, b"connect"));
+
+        let (socket, _) = listener.accept().unwrap();
+        let mut connection = Connection::new(socket, Some(&config));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut received = Vec::new();
+        while received.len() < b"connect".len() {
+            assert!(Instant::now() < deadline, "Timed out after {:?}", received);
+            match connection.read().unwrap() {
+                ReadResult::BytesReceived { buffer, byte_count } => {
+                    received.extend_from_slice(&buffer[..byte_count])
+                }
+                _ => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+
+        assert_eq!(received, b"connect");
+        assert_eq!(connection.handshake_duration(), None);
+        client.join().unwrap();
+        connection.close();
+    }
+}